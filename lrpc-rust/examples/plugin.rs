@@ -0,0 +1,21 @@
+//! A plugin serving its functions to the parent process,
+//! started with Connection::spawn
+
+use lrpc::*;
+
+#[fmt_function]
+fn reverse(s: String) -> String {
+    s.chars().rev().collect()
+}
+
+#[fmt_function]
+fn pid() -> u32 {
+    std::process::id()
+}
+
+fn main() {
+    let mut srv_fun = Fun::new();
+    srv_fun.regist("reverse", reverse);
+    srv_fun.regist("pid", pid);
+    serve_stdio(srv_fun);
+}
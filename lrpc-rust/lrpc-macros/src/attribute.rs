@@ -1,6 +1,61 @@
 use proc_macro::{Delimiter, TokenStream, TokenTree};
 
-fn fun_ret(vis: String, name: String, args: String, body: String, ret: String) -> TokenStream {
+#[derive(Default)]
+struct Attr {
+    name: Option<String>,
+}
+
+fn parse_attr(attr: TokenStream) -> Attr {
+    let mut a = Attr::default();
+    let mut key = String::new();
+    let check = |key: &str| match key {
+        "" => (),
+        "name" => panic!("fmt_function name needs a value, e.g. name = \"math.add\""),
+        _ => panic!("unknown fmt_function argument {}", key),
+    };
+    for node in attr {
+        match node {
+            TokenTree::Ident(ident) => key = ident.to_string(),
+            TokenTree::Literal(lit) => match key.as_str() {
+                "name" => {
+                    let lit = lit.to_string();
+                    if !lit.starts_with('"') {
+                        panic!("name must be a string literal");
+                    }
+                    a.name = Some(lit);
+                    key.clear();
+                }
+                _ => panic!("unknown fmt_function argument {}", key),
+            },
+            TokenTree::Punct(punct) => {
+                if punct.as_char() == ',' {
+                    check(&key);
+                    key.clear();
+                }
+            }
+            _ => (),
+        }
+    }
+    check(&key);
+    a
+}
+
+/// A parameter of type Context is not sent by the caller
+fn is_context(arg: &str) -> bool {
+    match arg.split_once(':') {
+        Some((_, ty)) => ty.rsplit("::").next().map(str::trim) == Some("Context"),
+        None => false,
+    }
+}
+
+fn fun_ret(
+    attr: Attr,
+    vis: String,
+    name: String,
+    args: String,
+    body: String,
+    ret: String,
+) -> TokenStream {
     let mut slf = String::new();
     let mut exp = String::new();
     for a in args.split_terminator(',') {
@@ -8,6 +63,10 @@ fn fun_ret(vis: String, name: String, args: String, body: String, ret: String) -
             slf = a.to_string() + ", ";
             continue;
         }
+        if is_context(a) {
+            exp.push_str(&format!("let {}=context();", a));
+            continue;
+        }
         exp.push_str(
             &format!(
                 "
@@ -41,8 +100,26 @@ fn fun_ret(vis: String, name: String, args: String, body: String, ret: String) -
         "
     }
     .to_string();
+    let wire = match attr.name {
+        Some(wire) => {
+            if !slf.is_empty() {
+                panic!("name cannot be used on methods");
+            }
+            format!(
+                "
+                #[allow(non_snake_case)]
+                {} mod {} {{
+                    pub const NAME: &str = {};
+                }}
+                ",
+                vis, name, wire
+            )
+        }
+        None => String::new(),
+    };
     format!(
         "
+        {}
         {} fn {}({}__q: &mut ByteQue) -> ByteQue {{
             let mut __r=ByteQue::new();
             {}
@@ -56,13 +133,14 @@ fn fun_ret(vis: String, name: String, args: String, body: String, ret: String) -
             __r
         }}
         ",
-        vis, name, slf, exp, name, ret, body, rst
+        wire, vis, name, slf, exp, name, ret, body, rst
     )
     .parse()
     .unwrap()
 }
 
-pub(super) fn fmt_function(input: TokenStream) -> TokenStream {
+pub(super) fn fmt_function(attr: TokenStream, input: TokenStream) -> TokenStream {
+    let attr = parse_attr(attr);
     let mut is_fn = false;
     let mut is_arg = false;
     let mut vis = String::new();
@@ -109,7 +187,7 @@ pub(super) fn fmt_function(input: TokenStream) -> TokenStream {
                             }
                         }
                         Delimiter::Brace => {
-                            return fun_ret(vis, name, args, group.to_string(), ret);
+                            return fun_ret(attr, vis, name, args, group.to_string(), ret);
                         }
                         _ => (),
                     }
@@ -8,8 +8,10 @@ pub fn common_store(input: TokenStream) -> TokenStream {
     derive::common_store(input)
 }
 
-/// The format function becomes fn (& mut ByteQue)-> ByteQue
+/// The format function becomes fn (& mut ByteQue)-> ByteQue,
+/// name = "..." adds a module of the same name holding the wire name as NAME.
+/// A parameter of type Context is filled with the context of the call
 #[proc_macro_attribute]
-pub fn fmt_function(_: TokenStream, input: TokenStream) -> TokenStream {
-    attribute::fmt_function(input)
+pub fn fmt_function(attr: TokenStream, input: TokenStream) -> TokenStream {
+    attribute::fmt_function(attr, input)
 }
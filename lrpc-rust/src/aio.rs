@@ -0,0 +1,376 @@
+//! Upload and duplex calls on async streams, enabled by the async feature.
+//! The frames, credit and window are those of the blocking calls,
+//! a side waiting for credit or items yields instead of blocking its thread.
+//! The streams are those of futures-io, which the async runtimes provide
+//! or adapt to.
+//!
+//! # Examples
+//!
+//! ```
+//! use lrpc::*;
+//!
+//! fn sum<'a>(_: ByteQue, rx: &'a mut AsyncReceiver) -> BoxFuture<'a, ByteQue> {
+//!     Box::pin(async move {
+//!         let mut n = 0u32;
+//!         while let Some(x) = rx.recv::<u32>().await {
+//!             n += x.unwrap();
+//!         }
+//!         let mut r = ByteQue::new();
+//!         Result::<u32>::Ok(n).store(&mut r);
+//!         r
+//!     })
+//! }
+//!
+//! let mut srv_fun = Fun::new();
+//! srv_fun.regist_upload_async("sum", sum);
+//! let (mut client, server) = pipe();
+//! let call = async move {
+//!     let mut up = AsyncUpload::call(&mut client, fun!("sum")).await?;
+//!     for i in 0..100u32 {
+//!         up.send(&i).await?;
+//!     }
+//!     up.finish::<u32>().await
+//! };
+//! // both on one thread, serve_async returns once the client is dropped
+//! let serve = serve_async(&srv_fun, server);
+//! let (_, sum) = futures::executor::block_on(futures::future::join(serve, call));
+//! assert_eq!(sum, Ok(4950));
+//! ```
+
+use crate::{
+    buf::send_data,
+    chan::{self, Window, END, ITEM},
+    context::Context,
+    frame,
+    fun::{Fun, Peer, Result},
+    link,
+    val::{ByteQue, Store},
+};
+use futures_io::{AsyncRead, AsyncWrite};
+use std::{
+    future::{poll_fn, Future},
+    io,
+    pin::Pin,
+    sync::atomic::Ordering,
+};
+
+/// The future returned by async upload and duplex functions
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Function registered with Fun::regist_upload_async
+pub(crate) type UploadFn =
+    for<'a, 'b> fn(ByteQue, &'a mut AsyncReceiver<'b>) -> BoxFuture<'a, ByteQue>;
+
+/// Function registered with Fun::regist_duplex_async
+pub(crate) type DuplexFn =
+    for<'a, 'b> fn(ByteQue, &'a mut AsyncDuplex<'b>) -> BoxFuture<'a, Result<()>>;
+
+pub(crate) trait AsyncIo: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncIo for T {}
+
+async fn write_all(s: &mut dyn AsyncIo, mut b: &[u8]) -> io::Result<()> {
+    while !b.is_empty() {
+        let n = poll_fn(|cx| Pin::new(&mut *s).poll_write(cx, b)).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        b = &b[n..];
+    }
+    poll_fn(|cx| Pin::new(&mut *s).poll_flush(cx)).await
+}
+
+/// Read up to b.len() bytes, failing at the end of the stream
+async fn read_some(s: &mut dyn AsyncIo, b: &mut [u8]) -> io::Result<usize> {
+    match poll_fn(|cx| Pin::new(&mut *s).poll_read(cx, b)).await? {
+        0 => Err(io::ErrorKind::UnexpectedEof.into()),
+        n => Ok(n),
+    }
+}
+
+/// Like keepalive::recv, the next frame that is not a heartbeat
+async fn recv(s: &mut dyn AsyncIo) -> io::Result<ByteQue> {
+    loop {
+        let mut size = 0usize;
+        let mut b = [0u8];
+        // maximum number of 64-bit computers
+        for i in 0..10 {
+            read_some(s, &mut b).await?;
+            size |= (b[0] as usize & 0x7f) << (7 * i);
+            if b[0] <= 0x7f {
+                break;
+            }
+        }
+        // read as it comes rather than trusting the length
+        let mut v = Vec::new();
+        let mut chunk = [0u8; 4096];
+        while v.len() < size {
+            let n = (size - v.len()).min(chunk.len());
+            let n = read_some(s, &mut chunk[..n]).await?;
+            v.extend_from_slice(&chunk[..n]);
+        }
+        if !v.is_empty() {
+            return Ok(ByteQue::from(v));
+        }
+    }
+}
+
+/// Chan on an async stream
+pub(crate) struct AsyncChan<'a> {
+    s: &'a mut dyn AsyncIo,
+    /// the caller's end, which stops sending when the function returns
+    client: bool,
+    w: Window,
+}
+
+impl<'a> AsyncChan<'a> {
+    fn new(s: &'a mut dyn AsyncIo, client: bool) -> Self {
+        AsyncChan {
+            s,
+            client,
+            w: Window::new(),
+        }
+    }
+
+    /// Caller side, send the call and make its chan
+    async fn call(s: &'a mut dyn AsyncIo, kind: u8, fun: ByteQue) -> Result<AsyncChan<'a>> {
+        let mut c = AsyncChan::new(s, true);
+        c.write(&send_data(frame::wrap(kind, fun))).await?;
+        Ok(c)
+    }
+
+    async fn write(&mut self, b: &[u8]) -> Result<()> {
+        match write_all(self.s, b).await {
+            Ok(()) => Ok(()),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+
+    async fn grant(&mut self, n: usize) -> Result<()> {
+        match n {
+            0 => Ok(()),
+            n => self.write(&chan::credit(n)).await,
+        }
+    }
+
+    /// Read one frame from the other side
+    async fn read(&mut self) -> Result<()> {
+        let q = match recv(self.s).await {
+            Ok(q) => q,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        // nothing subscribes on the stream of a call, events are dropped
+        if self.client && frame::push_kind(&q).is_some() {
+            return Ok(());
+        }
+        self.w.take(q)
+    }
+
+    async fn send(&mut self, q: &ByteQue) -> Result<()> {
+        if self.w.ended {
+            return Err(String::from("the stream is already finished"));
+        }
+        loop {
+            if self.client && self.w.end.is_some() {
+                return Err(String::from("the function has returned"));
+            }
+            if self.w.spend() {
+                break;
+            }
+            self.read().await?;
+            let n = self.w.prepay();
+            self.grant(n).await?;
+        }
+        self.write(&chan::frame(ITEM, q)).await
+    }
+
+    /// None once the other side has sent END
+    async fn recv(&mut self) -> Option<Result<ByteQue>> {
+        loop {
+            if let Some((q, n)) = self.w.next() {
+                return Some(self.grant(n).await.map(|_| q));
+            }
+            if self.w.end.is_some() {
+                return None;
+            }
+            if let Err(e) = self.read().await {
+                return Some(Err(e));
+            }
+        }
+    }
+
+    async fn end(&mut self, q: &ByteQue) -> Result<()> {
+        if self.w.ended {
+            return Ok(());
+        }
+        self.w.ended = true;
+        self.write(&chan::frame(END, q)).await
+    }
+
+    /// Server side, read and discard until the caller has sent END
+    async fn drain(&mut self) -> Result<()> {
+        while self.w.end.is_none() {
+            self.read().await?;
+            self.w.discard();
+        }
+        Ok(())
+    }
+
+    /// Caller side, send END, discard the unread items
+    /// and return the payload of the function's END
+    async fn finish(&mut self) -> Result<ByteQue> {
+        self.end(&ByteQue::new()).await?;
+        while let Some(r) = self.recv().await {
+            r?;
+        }
+        match self.w.end.take() {
+            Some(q) => Ok(q),
+            None => Err(String::from("the stream is already finished")),
+        }
+    }
+}
+
+/// Handed to async upload functions to read the items sent by the caller
+pub struct AsyncReceiver<'a>(AsyncChan<'a>);
+
+impl AsyncReceiver<'_> {
+    /// None after the caller has sent its last item
+    pub async fn recv<T: Store>(&mut self) -> Option<Result<T>> {
+        let r = self.0.recv().await;
+        r.map(|r| r.map(|mut q| T::restore(&mut q)))
+    }
+}
+
+/// Both ends of a duplex function on an async stream,
+/// given to async duplex functions and returned by AsyncDuplex::call.
+/// Items are buffered as by Duplex.
+/// The caller must finish the call, dropping it leaves the stream
+/// in the middle of the call
+pub struct AsyncDuplex<'a>(AsyncChan<'a>);
+
+impl<'a> AsyncDuplex<'a> {
+    /// Call a function registered with Fun::regist_duplex
+    /// or Fun::regist_duplex_async on the other end of the stream
+    pub async fn call<S>(stream: &'a mut S, fun: ByteQue) -> Result<AsyncDuplex<'a>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        AsyncChan::call(stream, frame::DUPLEX, fun)
+            .await
+            .map(AsyncDuplex)
+    }
+
+    pub async fn send<T: Store>(&mut self, item: &T) -> Result<()> {
+        let mut q = ByteQue::new();
+        item.store(&mut q);
+        self.0.send(&q).await
+    }
+
+    /// None after the other side has finished sending
+    pub async fn recv<T: Store>(&mut self) -> Option<Result<T>> {
+        let r = self.0.recv().await;
+        r.map(|r| r.map(|mut q| T::restore(&mut q)))
+    }
+
+    /// Caller side, stop sending, discard the unread items
+    /// and wait for the result of the function
+    pub async fn finish(mut self) -> Result<()> {
+        Store::restore(&mut self.0.finish().await?)
+    }
+}
+
+/// Caller side of an upload function on an async stream.
+/// Like AsyncDuplex, it must be finished
+pub struct AsyncUpload<'a>(AsyncChan<'a>);
+
+impl<'a> AsyncUpload<'a> {
+    /// Call a function registered with Fun::regist_upload
+    /// or Fun::regist_upload_async on the other end of the stream
+    pub async fn call<S>(stream: &'a mut S, fun: ByteQue) -> Result<AsyncUpload<'a>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        AsyncChan::call(stream, frame::UPLOAD, fun)
+            .await
+            .map(AsyncUpload)
+    }
+
+    /// Fails once the function has returned
+    pub async fn send<T: Store>(&mut self, item: &T) -> Result<()> {
+        let mut q = ByteQue::new();
+        item.store(&mut q);
+        self.0.send(&q).await
+    }
+
+    /// Stop sending and wait for the result of the function
+    pub async fn finish<T: Store>(mut self) -> Result<T> {
+        Store::restore(&mut self.0.finish().await?)
+    }
+}
+
+/// Serve the calls read from an async stream until it ends,
+/// on the task awaiting it rather than on a thread of its own.
+/// Upload and duplex calls run the functions registered with
+/// Fun::regist_upload_async and Fun::regist_duplex_async.
+/// Plain calls and notifications run in place and should be short,
+/// the other calls are answered with an error
+pub async fn serve_async<S>(srv_fun: &Fun, mut stream: S)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let context = Context {
+        connection: link::CONNECTIONS.fetch_add(1, Ordering::Relaxed),
+        ..Context::default()
+    };
+    if !srv_fun.connected(&context) {
+        return;
+    }
+    while let Ok(q) = recv(&mut stream).await {
+        if dispatch(srv_fun, &context, &mut stream, q).await.is_err() {
+            break;
+        }
+    }
+    srv_fun.disconnected(&context);
+}
+
+async fn dispatch(fun: &Fun, c: &Context, s: &mut dyn AsyncIo, mut q: ByteQue) -> Result<()> {
+    let peer = Peer {
+        connection: c.connection,
+        session: Some(&c.session),
+        ..Peer::default()
+    };
+    let r = match frame::kind(&q) {
+        Some(frame::UPLOAD) => {
+            frame::unwrap(&mut q);
+            let mut rx = AsyncReceiver(AsyncChan::new(s, false));
+            let r = fun.upload_async(peer, q, &mut rx).await;
+            rx.0.end(&r).await?;
+            return rx.0.drain().await;
+        }
+        Some(frame::DUPLEX) => {
+            frame::unwrap(&mut q);
+            let mut d = AsyncDuplex(AsyncChan::new(s, false));
+            let r = fun.duplex_async(peer, q, &mut d).await;
+            d.0.end(&r).await?;
+            return d.0.drain().await;
+        }
+        Some(frame::NOTIFY) => {
+            frame::unwrap(&mut q);
+            fun.invoke_as(peer, &mut q);
+            return Ok(());
+        }
+        // no heartbeats are sent back
+        Some(frame::PING) => return Ok(()),
+        None | Some(frame::CALL_ID) => fun.invoke_as(peer, &mut q),
+        Some(k) => {
+            let mut r = ByteQue::new();
+            Result::<()>::Err(format!("frame kind {} is not served asynchronously", k))
+                .store(&mut r);
+            r
+        }
+    };
+    match write_all(s, &send_data(r)).await {
+        Ok(()) => Ok(()),
+        Err(e) => Err(format!("{}", e)),
+    }
+}
@@ -0,0 +1,127 @@
+//! Pre-shared key handshake, both sides prove they know the key
+//!
+//! The client sends AUTH with its nonce and key id, the server answers with its nonce
+//! and the mac of both, the client sends its mac and the server replies
+//! Ok(()). A server that is not convinced closes the connection,
+//! as it does when the handshake takes longer than HANDSHAKE
+//! or a frame of it is longer than MAX_FRAME.
+
+use crate::{
+    buf::{recv_limited, send_data},
+    chan::Io,
+    frame,
+    fun::Result,
+    keepalive,
+    link::Link,
+    val::{ByteQue, Store},
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    io,
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::Duration,
+};
+
+const NONCE: usize = 32;
+
+/// How long a caller has to complete the handshake
+const HANDSHAKE: Duration = Duration::from_secs(5);
+
+/// The longest frame read before the caller is known
+const MAX_FRAME: usize = 1024;
+
+/// Like keepalive::recv, bounded by MAX_FRAME
+fn recv(s: &mut dyn Io) -> io::Result<ByteQue> {
+    loop {
+        let q = recv_limited(s, MAX_FRAME)?;
+        if !q.is_empty() {
+            return Ok(q);
+        }
+    }
+}
+
+fn nonce() -> io::Result<[u8; NONCE]> {
+    let mut n = [0; NONCE];
+    getrandom::getrandom(&mut n)?;
+    Ok(n)
+}
+
+fn mac(key: &[u8], side: &[u8], first: &[u8], second: &[u8]) -> Hmac<Sha256> {
+    let mut m = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    m.update(side);
+    m.update(first);
+    m.update(second);
+    m
+}
+
+/// Server side, before anything else is read, returns the id of the key.
+/// The link is closed if the handshake is not done within HANDSHAKE
+pub(crate) fn accept(link: &mut Link, keys: &HashMap<String, Vec<u8>>) -> io::Result<String> {
+    let (done, wait) = mpsc::channel::<()>();
+    let timer = link.clone();
+    thread::spawn(move || {
+        if let Err(RecvTimeoutError::Timeout) = wait.recv_timeout(HANDSHAKE) {
+            timer.close();
+        }
+    });
+    let r = handshake(link, keys);
+    drop(done);
+    r
+}
+
+fn handshake(s: &mut dyn Io, keys: &HashMap<String, Vec<u8>>) -> io::Result<String> {
+    let denied = || io::Error::from(io::ErrorKind::PermissionDenied);
+    let mut q = recv(s)?;
+    if frame::kind(&q) != Some(frame::AUTH) || q.len() < NONCE + 3 {
+        return Err(denied());
+    }
+    frame::unwrap(&mut q);
+    let theirs = q.pop_slice(NONCE).to_vec();
+    let id = String::restore(&mut q);
+    let key = keys.get(&id).ok_or_else(denied)?;
+    let ours = nonce()?;
+    let mut r = ours.to_vec();
+    r.extend(mac(key, b"server", &theirs, &ours).finalize().into_bytes());
+    s.write_all(&send_data(ByteQue::from(r)))?;
+    let proof = recv(s)?;
+    mac(key, b"client", &ours, &theirs)
+        .verify_slice(proof.as_slice())
+        .map_err(|_| denied())?;
+    let mut r = ByteQue::new();
+    Result::<()>::Ok(()).store(&mut r);
+    s.write_all(&send_data(r))?;
+    Ok(id)
+}
+
+/// Client side, before anything else is sent
+pub(crate) fn connect(s: &mut dyn Io, id: &str, key: &[u8]) -> Result<()> {
+    let failed = |e: io::Error| match e.kind() {
+        io::ErrorKind::UnexpectedEof => String::from("authentication failed"),
+        _ => e.to_string(),
+    };
+    let ours = nonce().map_err(failed)?;
+    let mut auth = ByteQue::from(ours.to_vec());
+    id.to_string().store(&mut auth);
+    let auth = frame::wrap(frame::AUTH, auth);
+    s.write_all(&send_data(auth)).map_err(failed)?;
+    let mut q = keepalive::recv(s).map_err(failed)?;
+    let r = q.as_slice();
+    if r.len() != 2 * NONCE {
+        // a server without a key replies with an error
+        return Result::<()>::restore(&mut q).and(Err(String::from("authentication failed")));
+    }
+    let (theirs, proof) = r.split_at(NONCE);
+    if mac(key, b"server", &ours, theirs)
+        .verify_slice(proof)
+        .is_err()
+    {
+        return Err(String::from("the server does not know the key"));
+    }
+    let proof = mac(key, b"client", theirs, &ours).finalize().into_bytes();
+    s.write_all(&send_data(ByteQue::from(proof.to_vec())))
+        .map_err(failed)?;
+    Store::restore(&mut keepalive::recv(s).map_err(failed)?)
+}
@@ -0,0 +1,256 @@
+//! Calls spread over several servers running the same functions
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use lrpc::*;
+//!
+//! let lb = Balancer::new(&["10.0.0.1:9009", "10.0.0.2:9009"], Strategy::RoundRobin);
+//! let area: Result<f64> = lb.invoke(fun!("circle_area", 2.0f64));
+//! let user: Result<String> = lb.invoke_key("alice", fun!("profile", "alice".to_string()));
+//! ```
+
+use crate::{
+    fun::Result,
+    pool::Pool,
+    tcp::Connection,
+    val::{ByteQue, Store},
+};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// How Balancer::invoke picks the server
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strategy {
+    /// Each server in turn
+    RoundRobin,
+    /// The server with the fewest calls waiting for a reply
+    LeastOutstanding,
+    /// The same call goes to the same server as long as it is up,
+    /// as with invoke_key using the call itself as the key
+    ConsistentHash,
+}
+
+/// Points of each server on the hash ring
+const REPLICAS: usize = 64;
+
+struct Endpoint {
+    addr: String,
+    pool: Pool,
+    outstanding: AtomicUsize,
+    ejected_until: Mutex<Option<Instant>>,
+}
+
+impl Endpoint {
+    fn up(&self, now: Instant) -> bool {
+        match *self.ejected_until.lock().unwrap() {
+            Some(t) => now >= t,
+            None => true,
+        }
+    }
+}
+
+struct Endpoints {
+    list: Vec<Arc<Endpoint>>,
+    /// Hash of each point and the index of its server, sorted
+    ring: Vec<(u64, usize)>,
+}
+
+/// Makes a connection to the server at an address
+type Connect = dyn Fn(&str) -> Result<Connection> + Send + Sync;
+
+/// A client of several servers, each with its own Pool of connections.
+/// A server that cannot be reached or drops a call is ejected
+/// and tried again after a while
+pub struct Balancer {
+    resolve: Box<dyn Fn() -> Vec<String> + Send + Sync>,
+    connect: Arc<Connect>,
+    strategy: Strategy,
+    eject_for: Duration,
+    endpoints: Mutex<Arc<Endpoints>>,
+    next: AtomicUsize,
+}
+
+fn hash(b: &[u8]) -> u64 {
+    // FNV-1a
+    b.iter().fold(0xcbf29ce484222325, |h, b| {
+        (h ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+impl Balancer {
+    pub fn new(addrs: &[&str], strategy: Strategy) -> Self {
+        let addrs: Vec<String> = addrs.iter().map(|a| a.to_string()).collect();
+        Balancer::with_resolver(move || addrs.clone(), strategy)
+    }
+
+    /// The addresses are those returned by resolve,
+    /// called now and by each refresh
+    pub fn with_resolver<F>(resolve: F, strategy: Strategy) -> Self
+    where
+        F: Fn() -> Vec<String> + Send + Sync + 'static,
+    {
+        let lb = Balancer {
+            resolve: Box::new(resolve),
+            connect: Arc::new(Connection::dial),
+            strategy,
+            eject_for: Duration::from_secs(5),
+            endpoints: Mutex::new(Arc::new(Endpoints {
+                list: Vec::new(),
+                ring: Vec::new(),
+            })),
+            next: AtomicUsize::new(0),
+        };
+        lb.refresh();
+        lb
+    }
+
+    /// How long a failed server is left out, 5 seconds by default
+    pub fn eject_for(mut self, d: Duration) -> Self {
+        self.eject_for = d;
+        self
+    }
+
+    /// Connect to the servers with connect rather than tcp,
+    /// e.g. with Connection::dial_tls
+    pub fn connect<F>(mut self, connect: F) -> Self
+    where
+        F: Fn(&str) -> Result<Connection> + Send + Sync + 'static,
+    {
+        self.connect = Arc::new(connect);
+        *self.endpoints.lock().unwrap() = Arc::new(Endpoints {
+            list: Vec::new(),
+            ring: Vec::new(),
+        });
+        self.refresh();
+        self
+    }
+
+    /// Resolve the addresses again, the connections
+    /// to the servers still there are kept
+    pub fn refresh(&self) {
+        let mut addrs = (self.resolve)();
+        addrs.sort();
+        addrs.dedup();
+        let mut endpoints = self.endpoints.lock().unwrap();
+        let list: Vec<Arc<Endpoint>> = addrs
+            .into_iter()
+            .map(
+                |addr| match endpoints.list.iter().find(|e| e.addr == addr) {
+                    Some(e) => e.clone(),
+                    None => Arc::new(Endpoint {
+                        pool: {
+                            let (connect, addr) = (self.connect.clone(), addr.clone());
+                            Pool::with(move || connect(&addr))
+                        },
+                        addr,
+                        outstanding: AtomicUsize::new(0),
+                        ejected_until: Mutex::new(None),
+                    }),
+                },
+            )
+            .collect();
+        let mut ring: Vec<(u64, usize)> = list
+            .iter()
+            .enumerate()
+            .flat_map(|(i, e)| {
+                (0..REPLICAS).map(move |r| (hash(format!("{}#{}", e.addr, r).as_bytes()), i))
+            })
+            .collect();
+        ring.sort_unstable();
+        *endpoints = Arc::new(Endpoints { list, ring });
+    }
+
+    /// Addresses of the servers that are not ejected
+    pub fn healthy(&self) -> Vec<String> {
+        let now = Instant::now();
+        let endpoints = self.endpoints.lock().unwrap().clone();
+        endpoints
+            .list
+            .iter()
+            .filter(|e| e.up(now))
+            .map(|e| e.addr.clone())
+            .collect()
+    }
+
+    /// Call a server picked by the strategy
+    pub fn invoke<T: Store>(&self, fun: ByteQue) -> Result<T> {
+        let key = match self.strategy {
+            Strategy::ConsistentHash => Some(hash(fun.as_slice())),
+            _ => None,
+        };
+        self.call(key, fun)
+    }
+
+    /// Call the server the key maps to on the hash ring,
+    /// the calls with the same key go to the same server while it is up
+    pub fn invoke_key<T: Store>(&self, key: &str, fun: ByteQue) -> Result<T> {
+        self.call(Some(hash(key.as_bytes())), fun)
+    }
+
+    fn call<T: Store>(&self, key: Option<u64>, fun: ByteQue) -> Result<T> {
+        let endpoints = self.endpoints.lock().unwrap().clone();
+        let mut tried = Vec::new();
+        loop {
+            let i = match self.pick(&endpoints, key, &tried) {
+                Some(i) => i,
+                None => return Err(String::from("no server is available")),
+            };
+            tried.push(i);
+            let e = &endpoints.list[i];
+            // a server that cannot be reached has not seen the call, try another
+            let mut conn = match e.pool.get() {
+                Ok(c) => c,
+                Err(_) => {
+                    self.eject(e);
+                    continue;
+                }
+            };
+            e.outstanding.fetch_add(1, Ordering::SeqCst);
+            let r = conn.invoke(fun);
+            e.outstanding.fetch_sub(1, Ordering::SeqCst);
+            if conn.is_broken() {
+                self.eject(e);
+            }
+            return r;
+        }
+    }
+
+    fn eject(&self, e: &Endpoint) {
+        *e.ejected_until.lock().unwrap() = Some(Instant::now() + self.eject_for);
+    }
+
+    /// Index of the server for the call, skipping those tried.
+    /// If every server is ejected they are all tried
+    fn pick(&self, endpoints: &Endpoints, key: Option<u64>, tried: &[usize]) -> Option<usize> {
+        let now = Instant::now();
+        let list = &endpoints.list;
+        let mut up: Vec<usize> = (0..list.len())
+            .filter(|i| !tried.contains(i) && list[*i].up(now))
+            .collect();
+        if up.is_empty() && tried.is_empty() {
+            up = (0..list.len()).collect();
+        }
+        if up.is_empty() {
+            return None;
+        }
+        if let Some(key) = key {
+            let start = endpoints.ring.partition_point(|(h, _)| *h < key);
+            let ring = endpoints.ring[start..]
+                .iter()
+                .chain(&endpoints.ring[..start]);
+            return ring.map(|(_, i)| *i).find(|i| up.contains(i));
+        }
+        match self.strategy {
+            Strategy::LeastOutstanding => up
+                .into_iter()
+                .min_by_key(|i| list[*i].outstanding.load(Ordering::SeqCst)),
+            _ => Some(up[self.next.fetch_add(1, Ordering::Relaxed) % up.len()]),
+        }
+    }
+}
@@ -0,0 +1,204 @@
+//! Failing fast while a server is down
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use lrpc::*;
+//! use std::time::Duration;
+//!
+//! let breaker = Breaker::new()
+//!     .error_rate(0.5, 10)
+//!     .open_for(Duration::from_secs(10));
+//! let pool = Pool::new("127.0.0.1:9009").breaker(breaker.clone());
+//! let r: Result<i32> = pool.invoke(fun!("double", 2));
+//! println!("{:?}", breaker.state());
+//! ```
+
+use crate::fun::Result;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// The state of a Breaker
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Circuit {
+    /// Calls are made, their failures counted
+    Closed,
+    /// Calls fail without being made
+    Open,
+    /// A few trial calls are made, the circuit closes if they succeed
+    HalfOpen,
+}
+
+struct State {
+    circuit: Circuit,
+    /// When the circuit opened, or when counting began while closed
+    since: Instant,
+    calls: u32,
+    failures: u32,
+    /// Trial calls made and those that succeeded while half-open
+    trials: u32,
+    passed: u32,
+}
+
+/// Stops the calls to a server after too many of them failed,
+/// shared by the connections it is set on.
+/// A call fails when the connection is lost or cannot be made,
+/// or the function returns an error unless transport_only is set
+#[derive(Clone)]
+pub struct Breaker {
+    transport_only: bool,
+    error_rate: f64,
+    min_calls: u32,
+    window: Duration,
+    open_for: Duration,
+    trials: u32,
+    state: Arc<Mutex<State>>,
+}
+
+impl Default for Breaker {
+    fn default() -> Self {
+        Breaker::new()
+    }
+}
+
+impl Breaker {
+    /// Opens when half of at least 10 calls in 10 seconds failed,
+    /// for 5 seconds before a trial call
+    pub fn new() -> Self {
+        Breaker {
+            transport_only: false,
+            error_rate: 0.5,
+            min_calls: 10,
+            window: Duration::from_secs(10),
+            open_for: Duration::from_secs(5),
+            trials: 1,
+            state: Arc::new(Mutex::new(State {
+                circuit: Circuit::Closed,
+                since: Instant::now(),
+                calls: 0,
+                failures: 0,
+                trials: 0,
+                passed: 0,
+            })),
+        }
+    }
+
+    /// Open when this part of the calls failed, once min_calls were made
+    pub fn error_rate(mut self, rate: f64, min_calls: u32) -> Self {
+        self.error_rate = rate;
+        self.min_calls = min_calls.max(1);
+        self
+    }
+
+    /// How long calls are counted before counting again
+    pub fn window(mut self, d: Duration) -> Self {
+        self.window = d;
+        self
+    }
+
+    /// How long to fail fast before trying the server again
+    pub fn open_for(mut self, d: Duration) -> Self {
+        self.open_for = d;
+        self
+    }
+
+    /// Only count the calls whose connection was lost or could not be made,
+    /// errors returned by the functions are not failures
+    pub fn transport_only(mut self) -> Self {
+        self.transport_only = true;
+        self
+    }
+
+    /// Trial calls that must succeed to close the circuit
+    pub fn trials(mut self, n: u32) -> Self {
+        self.trials = n.max(1);
+        self
+    }
+
+    /// The state now, half-open once the circuit was open long enough
+    pub fn state(&self) -> Circuit {
+        let mut s = self.state.lock().unwrap();
+        self.half_open(&mut s);
+        s.circuit
+    }
+
+    /// Calls and failures counted while closed
+    pub fn counts(&self) -> (u32, u32) {
+        let s = self.state.lock().unwrap();
+        (s.calls, s.failures)
+    }
+
+    fn half_open(&self, s: &mut State) {
+        if s.circuit == Circuit::Open && s.since.elapsed() >= self.open_for {
+            s.circuit = Circuit::HalfOpen;
+            s.trials = 0;
+            s.passed = 0;
+        }
+    }
+
+    /// Fail unless the circuit is closed or half-open
+    pub(crate) fn check(&self) -> Result<()> {
+        match self.state() {
+            Circuit::Open => Err(String::from("the circuit breaker is open")),
+            _ => Ok(()),
+        }
+    }
+
+    /// Like check, and take one of the trial calls when half-open
+    pub(crate) fn allow(&self) -> Result<()> {
+        let mut s = self.state.lock().unwrap();
+        self.half_open(&mut s);
+        match s.circuit {
+            Circuit::Closed => Ok(()),
+            Circuit::HalfOpen if s.trials < self.trials => {
+                s.trials += 1;
+                Ok(())
+            }
+            _ => Err(String::from("the circuit breaker is open")),
+        }
+    }
+
+    /// Count a call that lost the connection or got an error reply
+    pub(crate) fn record_call(&self, lost: bool, error: bool) {
+        self.record(!lost && (self.transport_only || !error))
+    }
+
+    /// Count a call, opening or closing the circuit
+    pub(crate) fn record(&self, ok: bool) {
+        let mut s = self.state.lock().unwrap();
+        let now = Instant::now();
+        match s.circuit {
+            Circuit::Closed => {
+                if now - s.since > self.window {
+                    s.since = now;
+                    s.calls = 0;
+                    s.failures = 0;
+                }
+                s.calls += 1;
+                s.failures += !ok as u32;
+                if s.calls >= self.min_calls
+                    && s.failures as f64 >= self.error_rate * s.calls as f64
+                {
+                    s.circuit = Circuit::Open;
+                    s.since = now;
+                }
+            }
+            Circuit::HalfOpen if !ok => {
+                s.circuit = Circuit::Open;
+                s.since = now;
+            }
+            Circuit::HalfOpen => {
+                s.passed += 1;
+                if s.passed >= self.trials {
+                    s.circuit = Circuit::Closed;
+                    s.since = now;
+                    s.calls = 0;
+                    s.failures = 0;
+                }
+            }
+            Circuit::Open => {}
+        }
+    }
+}
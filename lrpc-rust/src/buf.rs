@@ -1,6 +1,7 @@
 //! Add length to the actual data to judge the integrity of the data
 
 use crate::val::ByteQue;
+use std::io::{self, Read};
 
 #[derive(Debug)]
 pub struct RecvBuf {
@@ -8,6 +9,13 @@ pub struct RecvBuf {
     size: Option<usize>,
 }
 
+impl Default for RecvBuf {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl RecvBuf {
     #[inline]
     pub fn new() -> Self {
@@ -35,8 +43,8 @@ impl RecvBuf {
                         // 64-bit computer will overflow if greater than 9
                         if x == 9 || other[x] <= 0x7f {
                             let mut s = 0usize;
-                            for i in 0..=x {
-                                s |= (other[i] as usize & 0x7f) << 7 * i;
+                            for (i, v) in other[..=x].iter().enumerate() {
+                                s |= (*v as usize & 0x7f) << (7 * i);
                             }
                             self.size = Some(s);
                             let t = &other[x + 1..];
@@ -56,7 +64,7 @@ impl RecvBuf {
                         if x == 9 || self.buf[x] <= 0x7f {
                             let mut s = 0usize;
                             for i in 0..=x {
-                                s |= (self.buf.remove(0) as usize & 0x7f) << 7 * i;
+                                s |= (self.buf.remove(0) as usize & 0x7f) << (7 * i);
                             }
                             self.size = Some(s);
                             if self.buf.len() > s {
@@ -79,6 +87,11 @@ impl RecvBuf {
     pub fn len(&self) -> usize {
         self.buf.len()
     }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
 }
 
 impl From<RecvBuf> for ByteQue {
@@ -104,3 +117,32 @@ pub fn send_data(q: ByteQue) -> Vec<u8> {
     v.append(&mut Vec::<u8>::from(q));
     v
 }
+
+/// Read exactly one frame,
+/// the bytes of the frames behind it stay in the reader
+pub fn recv_data<R: Read + ?Sized>(r: &mut R) -> io::Result<ByteQue> {
+    recv_limited(r, usize::MAX)
+}
+
+/// Like recv_data, failing on a frame longer than max before reading it
+pub(crate) fn recv_limited<R: Read + ?Sized>(r: &mut R, max: usize) -> io::Result<ByteQue> {
+    let mut s = 0usize;
+    let mut b = [0u8];
+    // maximum number of 64-bit computers
+    for i in 0..10 {
+        r.read_exact(&mut b)?;
+        s |= (b[0] as usize & 0x7f) << (7 * i);
+        if b[0] <= 0x7f {
+            break;
+        }
+    }
+    if s > max {
+        return Err(io::ErrorKind::InvalidData.into());
+    }
+    let mut v = Vec::new();
+    Read::take(&mut *r, s as u64).read_to_end(&mut v)?;
+    if v.len() < s {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(ByteQue::from(v))
+}
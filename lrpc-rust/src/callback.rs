@@ -0,0 +1,45 @@
+//! Functions running in service can call back
+//! the functions the caller registered on its Connection
+
+use crate::{buf::send_data, chan::Io, frame, fun::Result, keepalive, val::ByteQue};
+use std::{
+    cell::RefCell,
+    sync::{Arc, Mutex},
+};
+
+/// The link back to the caller, shared by the threads running its calls.
+/// A callback holds it until the reply comes
+pub(crate) type Caller = Arc<Mutex<Box<dyn Io + Send>>>;
+
+thread_local! {
+    static CALLER: RefCell<Option<Caller>> = RefCell::new(None);
+}
+
+/// Set the link back to the caller of the connection served by this thread,
+/// returns the previous one
+pub(crate) fn set_caller(s: Option<Caller>) -> Option<Caller> {
+    CALLER.with(|c| c.replace(s))
+}
+
+/// The link set on this thread, for the threads running calls for it
+pub(crate) fn caller() -> Option<Caller> {
+    CALLER.with(|c| c.borrow().clone())
+}
+
+/// Call a function registered with Connection::callbacks by the caller
+/// of the function that is running.
+/// Not available in notifications, upload and duplex functions.
+pub fn callback<T: crate::Store>(fun: ByteQue) -> Result<T> {
+    let c = match caller() {
+        Some(c) => c,
+        None => return Err(String::from("there is no caller to call back")),
+    };
+    let mut s = c.lock().unwrap();
+    if let Err(e) = s.write_all(&send_data(frame::push(frame::CALLBACK, fun))) {
+        return Err(format!("{}", e));
+    }
+    match keepalive::recv(&mut **s) {
+        Ok(mut q) => crate::Store::restore(&mut q),
+        Err(e) => Err(format!("{}", e)),
+    }
+}
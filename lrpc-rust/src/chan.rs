@@ -0,0 +1,331 @@
+//! Streams in both directions on one connection.
+//! Each frame starts with a tag,
+//! an item may only be sent with a credit granted by the receiver.
+
+use crate::{
+    breaker::Breaker,
+    buf::send_data,
+    frame,
+    fun::Result,
+    keepalive,
+    val::{ByteQue, Store},
+};
+use std::{
+    collections::VecDeque,
+    io::{Read, Write},
+};
+
+pub(crate) const ITEM: u8 = 0;
+const CREDIT: u8 = 1;
+pub(crate) const END: u8 = 2;
+
+/// Items each side may send before the other grants more
+const WINDOW: usize = 32;
+
+pub(crate) trait Io: Read + Write {}
+
+impl<T: Read + Write> Io for T {}
+
+/// The credit of one end of a stream and the items it has read,
+/// the same for the blocking and the async streams
+pub(crate) struct Window {
+    credit: usize,
+    consumed: usize,
+    /// the first items of the inbox, already granted back while sending,
+    /// at most WINDOW so a blocked sender buffers a bounded number of items
+    prepaid: usize,
+    /// items the other side may still send
+    granted: usize,
+    inbox: VecDeque<ByteQue>,
+    /// the other side sent END with this payload
+    pub(crate) end: Option<ByteQue>,
+    pub(crate) ended: bool,
+}
+
+impl Window {
+    pub(crate) fn new() -> Self {
+        Window {
+            credit: WINDOW,
+            consumed: 0,
+            prepaid: 0,
+            granted: WINDOW,
+            inbox: VecDeque::new(),
+            end: None,
+            ended: false,
+        }
+    }
+
+    /// Take in a frame read from the other side
+    pub(crate) fn take(&mut self, mut q: ByteQue) -> Result<()> {
+        match u8::restore(&mut q) {
+            ITEM if self.granted == 0 => {
+                return Err(String::from("the other side sent an item without credit"))
+            }
+            ITEM => {
+                self.granted -= 1;
+                self.inbox.push_back(q);
+            }
+            CREDIT => self.credit = self.credit.saturating_add(usize::restore(&mut q)),
+            END => self.end = Some(q),
+            t => return Err(format!("unknown stream frame {}", t)),
+        }
+        Ok(())
+    }
+
+    /// Take a credit to send an item, false if there is none
+    pub(crate) fn spend(&mut self) -> bool {
+        if self.credit == 0 {
+            return false;
+        }
+        self.credit -= 1;
+        true
+    }
+
+    /// While waiting for credit, the credit to grant for the items read meanwhile.
+    /// The other side may be waiting for credit as well,
+    /// it is granted up to one more window rather than both waiting
+    pub(crate) fn prepay(&mut self) -> usize {
+        let n = (self.inbox.len() - self.prepaid).min(WINDOW - self.prepaid);
+        self.prepaid += n;
+        self.granted += n;
+        n
+    }
+
+    /// The next item read and the credit to grant for it, often none
+    pub(crate) fn next(&mut self) -> Option<(ByteQue, usize)> {
+        let q = self.inbox.pop_front()?;
+        if self.prepaid > 0 {
+            self.prepaid -= 1;
+            return Some((q, 0));
+        }
+        self.consumed += 1;
+        if self.consumed < WINDOW / 2 {
+            return Some((q, 0));
+        }
+        let n = self.consumed;
+        self.consumed = 0;
+        self.granted += n;
+        Some((q, n))
+    }
+
+    /// Forget the items read, they are not granted back
+    pub(crate) fn discard(&mut self) {
+        self.inbox.clear();
+        self.prepaid = 0;
+    }
+}
+
+/// A frame of the stream with its tag
+pub(crate) fn frame(tag: u8, q: &ByteQue) -> Vec<u8> {
+    let mut r = ByteQue::with_capacity(q.len() + 1);
+    tag.store(&mut r);
+    r.push_slice(q.as_slice());
+    send_data(r)
+}
+
+/// The frame granting n more items
+pub(crate) fn credit(n: usize) -> Vec<u8> {
+    let mut c = ByteQue::new();
+    n.store(&mut c);
+    frame(CREDIT, &c)
+}
+
+pub(crate) struct Chan<'a> {
+    s: &'a mut dyn Io,
+    /// caller side, events published meanwhile are kept here
+    events: Option<&'a mut VecDeque<ByteQue>>,
+    /// the caller's end, which stops sending when the function returns
+    client: bool,
+    w: Window,
+    /// caller side, counts the call once it is closed
+    breaker: Option<Breaker>,
+}
+
+impl<'a> Chan<'a> {
+    pub(crate) fn new(s: &'a mut dyn Io, events: Option<&'a mut VecDeque<ByteQue>>) -> Self {
+        Chan {
+            s,
+            client: events.is_some(),
+            events,
+            w: Window::new(),
+            breaker: None,
+        }
+    }
+
+    pub(crate) fn breaker(mut self, breaker: Option<Breaker>) -> Self {
+        self.breaker = breaker;
+        self
+    }
+
+    fn write(&mut self, b: &[u8]) -> Result<()> {
+        match self.s.write_all(b) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+
+    fn grant(&mut self, n: usize) -> Result<()> {
+        match n {
+            0 => Ok(()),
+            n => self.write(&credit(n)),
+        }
+    }
+
+    /// Read one frame from the other side
+    fn read(&mut self) -> Result<()> {
+        let q = match keepalive::recv(self.s) {
+            Ok(q) => q,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        if let (Some(events), Some(frame::PUBLISH)) = (&mut self.events, frame::push_kind(&q)) {
+            events.push_back(q);
+            return Ok(());
+        }
+        self.w.take(q)
+    }
+
+    pub(crate) fn send(&mut self, q: &ByteQue) -> Result<()> {
+        if self.w.ended {
+            return Err(String::from("the stream is already finished"));
+        }
+        loop {
+            if self.client && self.w.end.is_some() {
+                return Err(String::from("the function has returned"));
+            }
+            if self.w.spend() {
+                break;
+            }
+            self.read()?;
+            let n = self.w.prepay();
+            self.grant(n)?;
+        }
+        self.write(&frame(ITEM, q))
+    }
+
+    /// None once the other side has sent END
+    pub(crate) fn recv(&mut self) -> Option<Result<ByteQue>> {
+        loop {
+            if let Some((q, n)) = self.w.next() {
+                return Some(self.grant(n).map(|_| q));
+            }
+            if self.w.end.is_some() {
+                return None;
+            }
+            if let Err(e) = self.read() {
+                return Some(Err(e));
+            }
+        }
+    }
+
+    /// Stop sending, the payload tells the other side how it ended
+    pub(crate) fn end(&mut self, q: &ByteQue) -> Result<()> {
+        if self.w.ended {
+            return Ok(());
+        }
+        self.w.ended = true;
+        self.write(&frame(END, q))
+    }
+
+    /// Server side, read and discard until the caller has sent END
+    pub(crate) fn drain(&mut self) -> Result<()> {
+        while self.w.end.is_none() {
+            self.read()?;
+            self.w.discard();
+        }
+        Ok(())
+    }
+
+    /// Caller side, send END, discard the unread items
+    /// and return the payload of the function's END
+    pub(crate) fn close(&mut self) -> Result<ByteQue> {
+        let r = self.finish();
+        if let Some(b) = self.breaker.take() {
+            match &r {
+                // the payload is a Result, starting with true for an error
+                Ok(q) => b.record_call(false, q.as_slice().first() != Some(&0)),
+                Err(_) => b.record_call(true, true),
+            }
+        }
+        r
+    }
+
+    fn finish(&mut self) -> Result<ByteQue> {
+        self.end(&ByteQue::new())?;
+        while let Some(r) = self.recv() {
+            r?;
+        }
+        match self.w.end.take() {
+            Some(q) => Ok(q),
+            None => Err(String::from("the stream is already finished")),
+        }
+    }
+}
+
+/// Handed to upload functions to read the items sent by the caller
+pub struct Receiver<'a>(pub(crate) Chan<'a>);
+
+impl Receiver<'_> {
+    /// None after the caller has sent its last item
+    pub fn recv<T: Store>(&mut self) -> Option<Result<T>> {
+        self.0.recv().map(|r| r.map(|mut q| T::restore(&mut q)))
+    }
+}
+
+/// Both ends of a duplex function,
+/// given to the function and returned by Connection::duplex.
+/// Both sides may send without receiving, items that arrive
+/// while a side waits for credit are buffered until it receives,
+/// up to two windows; beyond that a side sending must receive.
+pub struct Duplex<'a>(pub(crate) Chan<'a>);
+
+impl Duplex<'_> {
+    pub fn send<T: Store>(&mut self, item: &T) -> Result<()> {
+        let mut q = ByteQue::new();
+        item.store(&mut q);
+        self.0.send(&q)
+    }
+
+    /// None after the other side has finished sending
+    pub fn recv<T: Store>(&mut self) -> Option<Result<T>> {
+        self.0.recv().map(|r| r.map(|mut q| T::restore(&mut q)))
+    }
+
+    /// Caller side, stop sending, discard the unread items
+    /// and wait for the result of the function
+    pub fn finish(mut self) -> Result<()> {
+        Store::restore(&mut self.0.close()?)
+    }
+}
+
+impl Drop for Duplex<'_> {
+    fn drop(&mut self) {
+        if self.0.client && !self.0.w.ended {
+            let _ = self.0.close();
+        }
+    }
+}
+
+/// Caller side of an upload function, returned by Connection::upload
+pub struct Upload<'a>(pub(crate) Chan<'a>);
+
+impl Upload<'_> {
+    /// Fails once the function has returned
+    pub fn send<T: Store>(&mut self, item: &T) -> Result<()> {
+        let mut q = ByteQue::new();
+        item.store(&mut q);
+        self.0.send(&q)
+    }
+
+    /// Stop sending and wait for the result of the function
+    pub fn finish<T: Store>(mut self) -> Result<T> {
+        Store::restore(&mut self.0.close()?)
+    }
+}
+
+impl Drop for Upload<'_> {
+    fn drop(&mut self) {
+        if !self.0.w.ended {
+            let _ = self.0.close();
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! What a function running in service knows about its call
+
+use std::{
+    any::{Any, TypeId},
+    cell::RefCell,
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+/// The call that is running, functions made with fmt_function
+/// get it by taking a parameter of this type
+#[derive(Clone, Debug, Default)]
+pub struct Context {
+    /// Address of the caller, empty if unknown
+    pub peer: String,
+    /// Who the caller proved to be, as in Call
+    pub identity: String,
+    /// Number of the connection in this process, 0 if there is none
+    pub connection: u64,
+    /// Number of the call in this process
+    pub request: u64,
+    /// The time the caller gave with Connection::deadline. Nothing stops
+    /// at it, a long function can check it to give up early
+    pub deadline: Option<Instant>,
+    /// State kept by the connection between calls
+    pub session: Session,
+}
+
+/// Values of the connection, at most one of each type,
+/// wrap values in types of their own to keep several of one type.
+/// Created when the connection is made and dropped when it ends,
+/// clones share the same values
+#[derive(Clone, Default)]
+pub struct Session(Arc<Mutex<HashMap<TypeId, Box<dyn Any + Send>>>>);
+
+impl Session {
+    /// Returns the value of this type it replaces
+    pub fn insert<T: Any + Send>(&self, value: T) -> Option<T> {
+        let old = self
+            .0
+            .lock()
+            .unwrap()
+            .insert(TypeId::of::<T>(), Box::new(value));
+        old.and_then(|v| v.downcast().ok()).map(|v| *v)
+    }
+
+    pub fn get<T: Any + Send + Clone>(&self) -> Option<T> {
+        self.with(|v: Option<&mut T>| v.cloned())
+    }
+
+    pub fn remove<T: Any + Send>(&self) -> Option<T> {
+        let old = self.0.lock().unwrap().remove(&TypeId::of::<T>());
+        old.and_then(|v| v.downcast().ok()).map(|v| *v)
+    }
+
+    /// Use the value of this type in place
+    pub fn with<T: Any + Send, R>(&self, f: impl FnOnce(Option<&mut T>) -> R) -> R {
+        let mut values = self.0.lock().unwrap();
+        f(values
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|v| v.downcast_mut()))
+    }
+}
+
+impl fmt::Debug for Session {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Session({} values)", self.0.lock().unwrap().len())
+    }
+}
+
+thread_local! {
+    static CONTEXT: RefCell<Option<Context>> = const { RefCell::new(None) };
+}
+
+static REQUESTS: AtomicU64 = AtomicU64::new(1);
+
+pub(crate) fn next_request() -> u64 {
+    REQUESTS.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Set the context of the call run by this thread, returns the previous one
+pub(crate) fn set_context(c: Option<Context>) -> Option<Context> {
+    CONTEXT.with(|x| x.replace(c))
+}
+
+/// The context of the running call,
+/// the default one if no call is running on this thread
+pub fn context() -> Context {
+    CONTEXT.with(|c| c.borrow().clone().unwrap_or_default())
+}
@@ -0,0 +1,331 @@
+//! A plain call starts with the function name,
+//! a frame starting with an empty name is followed by its kind.
+//! An empty frame is a heartbeat, see keepalive
+
+use crate::{
+    buf::send_data,
+    callback,
+    chan::{Chan, Duplex, Receiver},
+    fun::{Fun, Peer, Result, Sender},
+    keepalive::{self, Side},
+    link::Conn,
+    val::{ByteQue, Store},
+};
+use std::{
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The call names the function by FunId instead of its name
+pub(crate) const CALL_ID: u8 = 1;
+/// The call is executed without sending a reply
+pub(crate) const NOTIFY: u8 = 2;
+/// Several calls in one frame, answered by one frame
+pub(crate) const BATCH: u8 = 3;
+
+/// The reply is a sequence of Option<Result<T>> frames,
+/// Some(Ok) for each item and Some(Err) or None at the end
+pub(crate) const STREAM: u8 = 4;
+
+/// The caller streams items and gets one reply
+pub(crate) const UPLOAD: u8 = 5;
+/// Both sides stream items
+pub(crate) const DUPLEX: u8 = 6;
+/// Receive the events published to a topic
+pub(crate) const SUBSCRIBE: u8 = 7;
+pub(crate) const UNSUBSCRIBE: u8 = 8;
+/// Starts the pre-shared key handshake, see auth
+pub(crate) const AUTH: u8 = 9;
+/// Milliseconds the caller waits followed by the frame of the call
+pub(crate) const DEADLINE: u8 = 10;
+/// Key and attempt of a call made by Connection::retry followed by the call
+pub(crate) const RETRY: u8 = 11;
+/// The caller sends heartbeats every so many milliseconds and wants them back,
+/// there is no reply
+pub(crate) const PING: u8 = 12;
+
+/// Frames the server sends on its own start with PEER and their kind,
+/// replies never start with this byte
+pub(crate) const PEER: u8 = 0x80;
+/// The server calls a function registered on the Connection
+pub(crate) const CALLBACK: u8 = 1;
+/// An event of a subscribed topic
+pub(crate) const PUBLISH: u8 = 2;
+
+/// Batch flag, run the calls one after another
+pub(crate) const IN_ORDER: u8 = 1;
+/// Batch flag, skip the calls after the first error
+pub(crate) const STOP_ON_ERROR: u8 = 2;
+
+/// Kind of an extended frame, None for a plain call
+#[inline]
+pub(crate) fn kind(q: &ByteQue) -> Option<u8> {
+    match q.as_slice() {
+        [0, k, ..] => Some(*k),
+        _ => None,
+    }
+}
+
+/// Prefix the frame with its kind
+pub(crate) fn wrap(kind: u8, q: ByteQue) -> ByteQue {
+    let mut r = ByteQue::with_capacity(q.len() + 2);
+    String::new().store(&mut r);
+    kind.store(&mut r);
+    r.push_slice(q.as_slice());
+    r
+}
+
+/// Prefix a frame sent by the server on its own with its kind
+pub(crate) fn push(kind: u8, q: ByteQue) -> ByteQue {
+    let mut r = ByteQue::with_capacity(q.len() + 2);
+    PEER.store(&mut r);
+    kind.store(&mut r);
+    r.push_slice(q.as_slice());
+    r
+}
+
+/// Kind of a frame sent by the server on its own, None for a reply
+#[inline]
+pub(crate) fn push_kind(q: &ByteQue) -> Option<u8> {
+    match q.as_slice() {
+        [PEER, k, ..] => Some(*k),
+        _ => None,
+    }
+}
+
+/// Remove the kind prefix
+#[inline]
+pub(crate) fn unwrap(q: &mut ByteQue) {
+    q.pop_slice(2);
+}
+
+/// Store a list of frames, each with its length
+pub(crate) fn store_list(list: &[ByteQue], q: &mut ByteQue) {
+    list.len().store(q);
+    for x in list {
+        x.len().store(q);
+        q.push_slice(x.as_slice());
+    }
+}
+
+pub(crate) fn restore_list(q: &mut ByteQue) -> Vec<ByteQue> {
+    let s = usize::restore(q);
+    if s > q.len() {
+        // the data must be wrong
+        return Vec::new();
+    }
+    let mut v = Vec::with_capacity(s);
+    for _ in 0..s {
+        let l = usize::restore(q);
+        if l > q.len() {
+            return Vec::new();
+        }
+        v.push(ByteQue::from(q.pop_slice(l).to_vec()));
+    }
+    v
+}
+
+fn batch(fun: &Fun, peer: Peer, q: &mut ByteQue) -> ByteQue {
+    let flags = u8::restore(q);
+    let calls = restore_list(q);
+    let rets: Vec<ByteQue> = if flags & IN_ORDER != 0 {
+        let mut failed = false;
+        calls
+            .into_iter()
+            .map(|mut c| {
+                if failed {
+                    let mut r = ByteQue::new();
+                    Result::<()>::Err(String::from("not executed after an earlier error"))
+                        .store(&mut r);
+                    return r;
+                }
+                let r = fun.invoke_as(peer, &mut c);
+                failed = flags & STOP_ON_ERROR != 0 && r.as_slice().first() != Some(&0);
+                r
+            })
+            .collect()
+    } else {
+        concurrently(fun, peer, calls)
+    };
+    let mut r = ByteQue::new();
+    false.store(&mut r);
+    store_list(&rets, &mut r);
+    r
+}
+
+/// Threads running the calls of a concurrent batch
+const BATCH_THREADS: usize = 8;
+
+/// Run the calls on at most BATCH_THREADS threads, which can call back
+fn concurrently(fun: &Fun, peer: Peer, calls: Vec<ByteQue>) -> Vec<ByteQue> {
+    let calls: Vec<Mutex<ByteQue>> = calls.into_iter().map(Mutex::new).collect();
+    let next = AtomicUsize::new(0);
+    let caller = callback::caller();
+    let mut rets: Vec<(usize, ByteQue)> = thread::scope(|s| {
+        let h: Vec<_> = (0..calls.len().min(BATCH_THREADS))
+            .map(|_| {
+                s.spawn(|| {
+                    callback::set_caller(caller.clone());
+                    let mut rets = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        match calls.get(i) {
+                            Some(c) => rets.push((i, fun.invoke_as(peer, &mut c.lock().unwrap()))),
+                            None => return rets,
+                        }
+                    }
+                })
+            })
+            .collect();
+        h.into_iter().flat_map(|h| h.join().unwrap()).collect()
+    });
+    rets.sort_unstable_by_key(|(i, _)| *i);
+    rets.into_iter().map(|(_, r)| r).collect()
+}
+
+fn stream(fun: &Fun, peer: Peer, q: &mut ByteQue, w: &mut dyn Write) -> io::Result<()> {
+    let mut r = fun.stream_from(peer, q, &mut Sender::new(w));
+    let mut e = ByteQue::new();
+    if bool::restore(&mut r) {
+        Some(Result::<()>::Err(String::restore(&mut r))).store(&mut e);
+    } else {
+        None::<()>.store(&mut e);
+    }
+    w.write_all(&send_data(e))
+}
+
+fn upload(fun: &Fun, peer: Peer, q: &mut ByteQue, s: &mut Conn) -> Result<()> {
+    // the caller is busy sending items and cannot be called back
+    let caller = callback::set_caller(None);
+    let mut rx = Receiver(Chan::new(&mut s.link, None));
+    let r = fun.upload_from(peer, q, &mut rx);
+    callback::set_caller(caller);
+    rx.0.end(&r)?;
+    rx.0.drain()
+}
+
+fn duplex(fun: &Fun, peer: Peer, q: &mut ByteQue, s: &mut Conn) -> Result<()> {
+    let caller = callback::set_caller(None);
+    let mut d = Duplex(Chan::new(&mut s.link, None));
+    let r = fun.duplex_from(peer, q, &mut d);
+    callback::set_caller(caller);
+    d.0.end(&r)?;
+    d.0.drain()
+}
+
+fn subscribe(fun: &Fun, conn: &mut Conn, q: &mut ByteQue, on: bool) -> ByteQue {
+    let topic = String::restore(q);
+    let hub = match fun.hub() {
+        Some(hub) => hub,
+        None => {
+            let mut r = ByteQue::new();
+            Result::<()>::Err(String::from("publish/subscribe is not enabled")).store(&mut r);
+            return r;
+        }
+    };
+    if !fun.topic_allowed(&conn.identity, &topic) {
+        let mut r = ByteQue::new();
+        Result::<()>::Err(format!("permission denied to subscribe to {} topic", topic))
+            .store(&mut r);
+        return r;
+    }
+    if on {
+        if conn.subscriber.is_none() {
+            conn.subscriber = Some((hub.clone(), hub.join(conn.link.clone())));
+        }
+        if let Some((_, s)) = &conn.subscriber {
+            hub.subscribe(&topic, s);
+        }
+    } else if let Some((_, s)) = &conn.subscriber {
+        hub.unsubscribe(&topic, s);
+    }
+    let mut r = ByteQue::new();
+    Result::<()>::Ok(()).store(&mut r);
+    r
+}
+
+/// Execute the frame and write its replies,
+/// frames of a stream sent by the caller are read from the connection as well
+pub(crate) fn dispatch(fun: &Fun, conn: &mut Conn, mut q: ByteQue) -> io::Result<()> {
+    let (addr, identity) = (conn.peer.clone(), conn.identity.clone());
+    let session = conn.session.clone();
+    let peer = Peer {
+        addr: &addr,
+        identity: &identity,
+        connection: conn.id,
+        deadline: conn.deadline,
+        session: Some(&session),
+    };
+    match kind(&q) {
+        Some(NOTIFY) => {
+            unwrap(&mut q);
+            // the caller does not wait for a notification and cannot be called back
+            let caller = callback::set_caller(None);
+            fun.invoke_as(peer, &mut q);
+            callback::set_caller(caller);
+            Ok(())
+        }
+        Some(BATCH) => {
+            unwrap(&mut q);
+            conn.link.write_all(&send_data(batch(fun, peer, &mut q)))
+        }
+        Some(STREAM) => {
+            unwrap(&mut q);
+            stream(fun, peer, &mut q, &mut conn.link)
+        }
+        Some(UPLOAD) => {
+            unwrap(&mut q);
+            upload(fun, peer, &mut q, conn).map_err(io::Error::other)
+        }
+        Some(DUPLEX) => {
+            unwrap(&mut q);
+            duplex(fun, peer, &mut q, conn).map_err(io::Error::other)
+        }
+        Some(k) if k == SUBSCRIBE || k == UNSUBSCRIBE => {
+            unwrap(&mut q);
+            let r = subscribe(fun, conn, &mut q, k == SUBSCRIBE);
+            conn.link.write_all(&send_data(r))
+        }
+        Some(DEADLINE) => {
+            unwrap(&mut q);
+            let ms = u64::restore(&mut q);
+            if kind(&q) == Some(DEADLINE) {
+                let mut r = ByteQue::new();
+                Result::<()>::Err(String::from("a call has only one deadline")).store(&mut r);
+                return conn.link.write_all(&send_data(r));
+            }
+            conn.deadline = Instant::now().checked_add(Duration::from_millis(ms));
+            let r = dispatch(fun, conn, q);
+            conn.deadline = None;
+            r
+        }
+        Some(RETRY) => {
+            unwrap(&mut q);
+            let key = u64::restore(&mut q);
+            let attempt = u32::restore(&mut q);
+            let r = fun.invoke_keyed(peer, key, attempt, &mut q);
+            conn.link.write_all(&send_data(r))
+        }
+        Some(PING) => {
+            unwrap(&mut q);
+            let asked = Duration::from_millis(u64::restore(&mut q));
+            // the server's own heartbeats may be often enough
+            if !conn.answering && fun.keepalive_interval().map_or(true, |i| i > asked) {
+                conn.answering = true;
+                keepalive::keepalive(conn.link.clone(), asked, Side::Answer);
+            }
+            Ok(())
+        }
+        Some(AUTH) => {
+            let mut r = ByteQue::new();
+            Result::<()>::Err(String::from("the server does not require a key")).store(&mut r);
+            conn.link.write_all(&send_data(r))
+        }
+        _ => conn.link.write_all(&send_data(fun.invoke_as(peer, &mut q))),
+    }
+}
@@ -17,8 +17,25 @@
 //! assert_eq!(rst, Ok(12));
 //! ```
 
-use crate::val::{ByteQue, Store};
-use std::collections::HashMap;
+#[cfg(feature = "async")]
+use crate::aio;
+use crate::{
+    buf::send_data,
+    chan::{Duplex, Receiver},
+    context::{next_request, set_context, Context, Session},
+    frame, keepalive,
+    limit::{Limit, Limiter},
+    pubsub::Hub,
+    retry::{Dedup, Seen},
+    val::{ByteQue, Store},
+};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    io::Write,
+    panic::{self, AssertUnwindSafe},
+    time::{Duration, Instant},
+};
 
 pub type Result<T> = std::result::Result<T, String>;
 
@@ -48,35 +65,760 @@ where
     }
 }
 
+/// Stable numeric id of a function name (32-bit FNV-1a),
+/// a call made with it carries a varint instead of the name
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FunId(pub u32);
+
+impl FunId {
+    pub const fn new(name: &str) -> Self {
+        let b = name.as_bytes();
+        let mut h = 0x811c9dc5u32;
+        let mut i = 0;
+        while i < b.len() {
+            h ^= b[i] as u32;
+            h = h.wrapping_mul(0x01000193);
+            i += 1;
+        }
+        FunId(h)
+    }
+
+    /// The start of a call made by this id, the arguments go after it
+    pub fn call(self) -> ByteQue {
+        let mut q = ByteQue::new();
+        String::new().store(&mut q);
+        frame::CALL_ID.store(&mut q);
+        (self.0 as usize).store(&mut q);
+        q
+    }
+}
+
+impl From<&str> for FunId {
+    #[inline]
+    fn from(name: &str) -> Self {
+        FunId::new(name)
+    }
+}
+
+impl Store for FunId {
+    #[inline]
+    fn store(&self, q: &mut ByteQue) {
+        self.0.store(q);
+    }
+    #[inline]
+    fn restore(q: &mut ByteQue) -> Self {
+        FunId(u32::restore(q))
+    }
+}
+
+/// What interceptors know about a call
+pub struct Call<'a> {
+    pub name: &'a str,
+    /// Address of the caller, empty if unknown
+    pub peer: &'a str,
+    /// Who the caller proved to be, e.g. psk:{id}, tls:{subject}
+    /// or uid:{uid}, empty if it did not
+    pub identity: &'a str,
+}
+
+/// Where a call comes from
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Peer<'a> {
+    pub(crate) addr: &'a str,
+    pub(crate) identity: &'a str,
+    pub(crate) connection: u64,
+    pub(crate) deadline: Option<Instant>,
+    pub(crate) session: Option<&'a Session>,
+}
+
+#[derive(Clone, Copy)]
+enum Handler {
+    Call(fn(&mut ByteQue) -> ByteQue),
+    Stream(fn(&mut ByteQue, &mut Sender) -> Result<()>),
+    Upload(fn(&mut ByteQue, &mut Receiver) -> ByteQue),
+    Duplex(fn(&mut ByteQue, &mut Duplex) -> Result<()>),
+    #[cfg(feature = "async")]
+    AsyncUpload(aio::UploadFn),
+    #[cfg(feature = "async")]
+    AsyncDuplex(aio::DuplexFn),
+}
+
+impl Handler {
+    fn kind(&self) -> &'static str {
+        match self {
+            Handler::Call(_) => "a plain",
+            Handler::Stream(_) => "a stream",
+            Handler::Upload(_) => "an upload",
+            Handler::Duplex(_) => "a duplex",
+            #[cfg(feature = "async")]
+            Handler::AsyncUpload(_) => "an async upload",
+            #[cfg(feature = "async")]
+            Handler::AsyncDuplex(_) => "an async duplex",
+        }
+    }
+}
+
+/// Handed to stream functions to send items to the caller one by one
+pub struct Sender<'a> {
+    w: &'a mut dyn Write,
+}
+
+impl<'a> Sender<'a> {
+    pub(crate) fn new(w: &'a mut dyn Write) -> Self {
+        Sender { w }
+    }
+
+    /// Fails once the caller is gone
+    pub fn send<T: Store>(&mut self, item: &T) -> Result<()> {
+        let mut q = ByteQue::new();
+        true.store(&mut q);
+        false.store(&mut q);
+        item.store(&mut q);
+        match self.w.write_all(&send_data(q)) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+}
+
+type Around =
+    Box<dyn Fn(&Call, &mut ByteQue, &dyn Fn(&mut ByteQue) -> ByteQue) -> ByteQue + Send + Sync>;
+
+type OnPanic = Box<dyn Fn(&Call, &str) + Send + Sync>;
+type Hook = Box<dyn Fn(&Context) + Send + Sync>;
+
 pub struct Fun {
-    fun: HashMap<String, fn(&mut ByteQue) -> ByteQue>,
+    fun: HashMap<String, Handler>,
+    ids: HashMap<FunId, (String, Handler)>,
+    around: Vec<Around>,
+    on_panic: Option<OnPanic>,
+    on_connect: Option<Hook>,
+    on_disconnect: Option<Hook>,
+    hub: Option<Hub>,
+    #[cfg(feature = "psk")]
+    keys: HashMap<String, Vec<u8>>,
+    acl: HashMap<String, Vec<String>>,
+    topics: HashMap<String, Vec<String>>,
+    limiter: Limiter,
+    idempotent: HashSet<String>,
+    dedup: Dedup,
+    keepalive: Option<Duration>,
+}
+
+impl Default for Fun {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Fun {
     pub fn new() -> Self {
         Fun {
             fun: HashMap::new(),
+            ids: HashMap::new(),
+            around: Vec::new(),
+            on_panic: None,
+            on_connect: None,
+            on_disconnect: None,
+            hub: None,
+            #[cfg(feature = "psk")]
+            keys: HashMap::new(),
+            acl: HashMap::new(),
+            topics: HashMap::new(),
+            limiter: Limiter::default(),
+            idempotent: HashSet::new(),
+            dedup: Dedup::default(),
+            keepalive: None,
         }
     }
 
+    /// Registering the same name twice is a programming error and panics,
+    /// as is a name whose FunId collides with a registered one
     pub fn regist(&mut self, name: &str, f: fn(&mut ByteQue) -> ByteQue) {
-        self.fun.insert(String::from(name), f);
+        self.add(name, Handler::Call(f));
     }
 
-    pub fn invoke(&self, q: &mut ByteQue) -> ByteQue {
+    /// Like regist, running the function again with the same arguments
+    /// does no harm, so a call retried by Connection::retry can run again
+    pub fn regist_idempotent(&mut self, name: &str, f: fn(&mut ByteQue) -> ByteQue) {
+        self.add(name, Handler::Call(f));
+        self.idempotent.insert(String::from(name));
+    }
+
+    /// The function sends any number of items through the Sender,
+    /// the caller reads them with Connection::stream
+    pub fn regist_stream(&mut self, name: &str, f: fn(&mut ByteQue, &mut Sender) -> Result<()>) {
+        self.add(name, Handler::Stream(f));
+    }
+
+    /// The function reads any number of items from the Receiver
+    /// and replies once like a function registered with regist,
+    /// the caller sends the items with Connection::upload
+    pub fn regist_upload(&mut self, name: &str, f: fn(&mut ByteQue, &mut Receiver) -> ByteQue) {
+        self.add(name, Handler::Upload(f));
+    }
+
+    /// The function and the caller both send and receive items,
+    /// the caller opens it with Connection::duplex
+    pub fn regist_duplex(&mut self, name: &str, f: fn(&mut ByteQue, &mut Duplex) -> Result<()>) {
+        self.add(name, Handler::Duplex(f));
+    }
+
+    /// Like regist_upload, the function awaits the items instead of
+    /// blocking for them, it is served by serve_async
+    #[cfg(feature = "async")]
+    pub fn regist_upload_async(&mut self, name: &str, f: aio::UploadFn) {
+        self.add(name, Handler::AsyncUpload(f));
+    }
+
+    /// Like regist_duplex, the function is served by serve_async
+    #[cfg(feature = "async")]
+    pub fn regist_duplex_async(&mut self, name: &str, f: aio::DuplexFn) {
+        self.add(name, Handler::AsyncDuplex(f));
+    }
+
+    fn add(&mut self, name: &str, h: Handler) {
+        if self.fun.contains_key(name) {
+            panic!("{} function is already registered", name);
+        }
+        if self.ids.contains_key(&FunId::new(name)) {
+            panic!("{} function id collides with a registered function", name);
+        }
+        self.fun.insert(String::from(name), h);
+        self.ids.insert(FunId::new(name), (String::from(name), h));
+    }
+
+    /// Functions registered through the returned registrar
+    /// are called as "namespace.name"
+    pub fn service(&mut self, namespace: &str) -> Service<'_> {
+        Service {
+            fun: self,
+            namespace: String::from(namespace),
+        }
+    }
+
+    /// Move all functions of other into self, its interceptors are dropped.
+    /// If any name exists in both, nothing is moved
+    /// and the conflicting names are returned as an error.
+    pub fn merge(&mut self, other: Fun) -> Result<()> {
+        let mut names: Vec<&String> = other
+            .fun
+            .keys()
+            .filter(|k| self.fun.contains_key(*k) || self.ids.contains_key(&FunId::new(k.as_str())))
+            .collect();
+        if !names.is_empty() {
+            names.sort();
+            return Err(format!(
+                "{} functions are already registered",
+                names
+                    .iter()
+                    .map(|s| s.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(", ")
+            ));
+        }
+        self.fun.extend(other.fun);
+        self.ids.extend(other.ids);
+        self.idempotent.extend(other.idempotent);
+        Ok(())
+    }
+
+    /// Interceptors wrap every registered function,
+    /// the first one added is the outermost.
+    /// next runs the rest of the chain and the function itself.
+    pub fn around<F>(&mut self, f: F)
+    where
+        F: Fn(&Call, &mut ByteQue, &dyn Fn(&mut ByteQue) -> ByteQue) -> ByteQue
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.around.push(Box::new(f));
+    }
+
+    /// Runs with the argument bytes before the function,
+    /// an error is returned to the caller instead of calling it
+    pub fn before<F>(&mut self, f: F)
+    where
+        F: Fn(&Call, &[u8]) -> Result<()> + Send + Sync + 'static,
+    {
+        self.around(move |call, q, next| match f(call, q.as_slice()) {
+            Ok(()) => next(q),
+            Err(e) => error(e),
+        });
+    }
+
+    /// Runs with the result bytes after the function
+    pub fn after<F>(&mut self, f: F)
+    where
+        F: Fn(&Call, &[u8]) + Send + Sync + 'static,
+    {
+        self.around(move |call, q, next| {
+            let r = next(q);
+            f(call, r.as_slice());
+            r
+        });
+    }
+
+    /// A panicking function returns an error to the caller
+    /// and is reported here with the panic message,
+    /// a panic of the hook itself is ignored
+    pub fn on_panic<F>(&mut self, f: F)
+    where
+        F: Fn(&Call, &str) + Send + Sync + 'static,
+    {
+        self.on_panic = Some(Box::new(f));
+    }
+
+    /// Called on the thread of each connection before its first call,
+    /// the session of the context can be filled here.
+    /// If it panics the connection is closed without on_disconnect
+    pub fn on_connect<F>(&mut self, f: F)
+    where
+        F: Fn(&Context) + Send + Sync + 'static,
+    {
+        self.on_connect = Some(Box::new(f));
+    }
+
+    /// Called when a connection ends, before its session is dropped.
+    /// A panic in it is caught and ignored
+    pub fn on_disconnect<F>(&mut self, f: F)
+    where
+        F: Fn(&Context) + Send + Sync + 'static,
+    {
+        self.on_disconnect = Some(Box::new(f));
+    }
+
+    /// False if the hook panicked
+    pub(crate) fn connected(&self, c: &Context) -> bool {
+        match &self.on_connect {
+            Some(h) => panic::catch_unwind(AssertUnwindSafe(|| h(c))).is_ok(),
+            None => true,
+        }
+    }
+
+    pub(crate) fn disconnected(&self, c: &Context) {
+        if let Some(h) = &self.on_disconnect {
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| h(c)));
+        }
+    }
+
+    /// Callers can subscribe to the topics of the hub
+    pub fn pubsub(&mut self, hub: Hub) {
+        self.hub = Some(hub);
+    }
+
+    pub(crate) fn hub(&self) -> Option<&Hub> {
+        self.hub.as_ref()
+    }
+
+    /// Callers must prove they know the pre-shared key
+    /// with Connection::authenticate, others are disconnected
+    #[cfg(feature = "psk")]
+    pub fn psk(&mut self, key: &[u8]) {
+        self.psk_id("", key)
+    }
+
+    /// Like psk, one of several keys told apart by their id.
+    /// Callers using it have the identity psk:{id}
+    #[cfg(feature = "psk")]
+    pub fn psk_id(&mut self, id: &str, key: &[u8]) {
+        self.keys.insert(id.to_string(), key.to_vec());
+    }
+
+    #[cfg(feature = "psk")]
+    pub(crate) fn keys(&self) -> Option<&HashMap<String, Vec<u8>>> {
+        match self.keys.is_empty() {
+            true => None,
+            false => Some(&self.keys),
+        }
+    }
+
+    /// Send heartbeats to the callers at this interval, and disconnect
+    /// those that send nothing for three intervals while no call runs.
+    /// Callers idle that long should set Connection::keepalive too.
+    /// Intervals under 10ms are taken as 10ms
+    pub fn keepalive(&mut self, interval: Duration) {
+        self.keepalive = Some(interval.max(keepalive::MIN_INTERVAL));
+    }
+
+    pub(crate) fn keepalive_interval(&self) -> Option<Duration> {
+        self.keepalive
+    }
+
+    /// Let the callers with the identity call the functions matching
+    /// the patterns, a pattern ending with * matches the names starting
+    /// with the rest. The identity * stands for every caller.
+    /// Once a rule is added, the calls no rule allows are denied,
+    /// a denied caller is not told whether the function exists
+    pub fn allow(&mut self, identity: &str, patterns: &[&str]) {
+        self.acl
+            .entry(identity.to_string())
+            .or_default()
+            .extend(patterns.iter().map(|p| p.to_string()));
+    }
+
+    /// Like allow, for the topics of the hub the callers may subscribe to.
+    /// Once a rule is added by either, the topics no rule allows are denied
+    pub fn allow_topics(&mut self, identity: &str, patterns: &[&str]) {
+        self.topics
+            .entry(identity.to_string())
+            .or_default()
+            .extend(patterns.iter().map(|p| p.to_string()));
+    }
+
+    fn allowed(&self, identity: &str, name: &str) -> bool {
+        self.acl.is_empty() || permits(&self.acl, identity, name)
+    }
+
+    pub(crate) fn topic_allowed(&self, identity: &str, topic: &str) -> bool {
+        (self.acl.is_empty() && self.topics.is_empty()) || permits(&self.topics, identity, topic)
+    }
+
+    /// Calls going over the limit get an error starting with
+    /// "resource exhausted" without running.
+    /// The pattern is matched as in allow
+    pub fn limit(&mut self, pattern: &str, limit: Limit) {
+        self.limiter.add(pattern, limit);
+    }
+
+    fn chain(
+        &self,
+        i: usize,
+        call: &Call,
+        f: &dyn Fn(&mut ByteQue) -> ByteQue,
+        q: &mut ByteQue,
+    ) -> ByteQue {
+        match self.around.get(i) {
+            Some(a) => a(call, q, &|q| self.chain(i + 1, call, f, q)),
+            None => f(q),
+        }
+    }
+
+    /// The function the call names, by its name or by its FunId,
+    /// if the caller with the identity may call it
+    fn find(&self, identity: &str, q: &mut ByteQue) -> Result<(&str, Handler)> {
         let name = String::restore(q);
-        match self.fun.get(&name) {
-            Some(f) => f(q),
-            None => {
-                let mut r = ByteQue::new();
-                true.store(&mut r);
-                format!("{} function not found", name).store(&mut r);
-                r
+        if name.is_empty() {
+            return match u8::restore(q) {
+                frame::CALL_ID => {
+                    let id = FunId(usize::restore(q) as u32);
+                    match self.ids.get(&id) {
+                        Some((name, h)) if self.allowed(identity, name) => Ok((name.as_str(), *h)),
+                        _ => Err(format!("function id {} not found", id.0)),
+                    }
+                }
+                k => Err(format!("unknown frame kind {}", k)),
+            };
+        }
+        if !self.allowed(identity, &name) {
+            return Err(format!("permission denied to call {} function", name));
+        }
+        self.fun
+            .get_key_value(&name)
+            .map(|(name, h)| (name.as_str(), *h))
+            .ok_or_else(|| format!("{} function not found", name))
+    }
+
+    /// Run the function inside the interceptors and catch its panic
+    fn run(
+        &self,
+        peer: Peer,
+        name: &str,
+        f: &dyn Fn(&mut ByteQue) -> ByteQue,
+        q: &mut ByteQue,
+    ) -> ByteQue {
+        let call = &peer.call(name);
+        let _permit = match self.limiter.acquire(call) {
+            Ok(p) => p,
+            Err(e) => return error(e),
+        };
+        let outer = set_context(Some(Context {
+            peer: peer.addr.to_string(),
+            identity: peer.identity.to_string(),
+            connection: peer.connection,
+            request: next_request(),
+            deadline: peer.deadline,
+            session: peer.session.cloned().unwrap_or_default(),
+        }));
+        let r = panic::catch_unwind(AssertUnwindSafe(|| {
+            if self.around.is_empty() {
+                return f(q);
+            }
+            self.chain(0, call, f, q)
+        }));
+        set_context(outer);
+        match r {
+            Ok(r) => r,
+            Err(e) => {
+                let msg = match e.downcast_ref::<&str>() {
+                    Some(s) => s.to_string(),
+                    None => match e.downcast_ref::<String>() {
+                        Some(s) => s.clone(),
+                        None => String::from("unknown panic"),
+                    },
+                };
+                if let Some(h) = &self.on_panic {
+                    // a panicking hook must not take the connection down either
+                    let _ = panic::catch_unwind(AssertUnwindSafe(|| h(call, &msg)));
+                }
+                error(format!("{} function panicked: {}", call.name, msg))
+            }
+        }
+    }
+
+    /// The call is named either by its name or by its FunId
+    pub fn invoke(&self, q: &mut ByteQue) -> ByteQue {
+        self.invoke_from("", q)
+    }
+
+    /// Same as invoke, peer is passed to the interceptors
+    pub fn invoke_from(&self, peer: &str, q: &mut ByteQue) -> ByteQue {
+        let peer = Peer {
+            addr: peer,
+            ..Peer::default()
+        };
+        self.invoke_as(peer, q)
+    }
+
+    pub(crate) fn invoke_as(&self, peer: Peer, q: &mut ByteQue) -> ByteQue {
+        match self.find(peer.identity, q) {
+            Ok((name, Handler::Call(f))) => self.run(peer, name, &f, q),
+            Ok((name, h)) => mismatch(name, h),
+            Err(e) => error(e),
+        }
+    }
+
+    /// A call made with a key by Connection::retry, see retry
+    pub(crate) fn invoke_keyed(
+        &self,
+        peer: Peer,
+        key: u64,
+        attempt: u32,
+        q: &mut ByteQue,
+    ) -> ByteQue {
+        let key = (caller(peer.identity, peer.addr).to_string(), key);
+        if let Seen::Reply(r) = self.dedup.begin(&key) {
+            return ByteQue::from(r);
+        }
+        let r = match self.find(peer.identity, &mut ByteQue::from(q.as_slice().to_vec())) {
+            Ok((name, Handler::Call(_))) if attempt > 0 && !self.idempotent.contains(name) => {
+                error(format!(
+                    "{} function is not idempotent and may have run before the connection was lost",
+                    name
+                ))
             }
+            _ => self.invoke_as(peer, q),
+        };
+        self.dedup.end(key, r.as_slice());
+        r
+    }
+
+    /// Call a stream function, the returned Result<()>
+    /// tells whether it ended normally.
+    /// For the interceptors the stream function returns this Result<()>.
+    pub(crate) fn stream_from(&self, peer: Peer, q: &mut ByteQue, tx: &mut Sender) -> ByteQue {
+        match self.find(peer.identity, q) {
+            Ok((name, Handler::Stream(f))) => {
+                let tx = RefCell::new(tx);
+                let f = |q: &mut ByteQue| {
+                    let mut r = ByteQue::new();
+                    f(q, &mut tx.borrow_mut()).store(&mut r);
+                    r
+                };
+                self.run(peer, name, &f, q)
+            }
+            Ok((name, h)) => mismatch(name, h),
+            Err(e) => error(e),
+        }
+    }
+
+    pub(crate) fn upload_from(&self, peer: Peer, q: &mut ByteQue, rx: &mut Receiver) -> ByteQue {
+        match self.find(peer.identity, q) {
+            Ok((name, Handler::Upload(f))) => {
+                let rx = RefCell::new(rx);
+                let f = |q: &mut ByteQue| f(q, &mut rx.borrow_mut());
+                self.run(peer, name, &f, q)
+            }
+            Ok((name, h)) => mismatch(name, h),
+            Err(e) => error(e),
+        }
+    }
+
+    /// Like stream_from, the Result<()> of the duplex function is returned
+    pub(crate) fn duplex_from(&self, peer: Peer, q: &mut ByteQue, d: &mut Duplex) -> ByteQue {
+        match self.find(peer.identity, q) {
+            Ok((name, Handler::Duplex(f))) => {
+                let d = RefCell::new(d);
+                let f = |q: &mut ByteQue| {
+                    let mut r = ByteQue::new();
+                    f(q, &mut d.borrow_mut()).store(&mut r);
+                    r
+                };
+                self.run(peer, name, &f, q)
+            }
+            Ok((name, h)) => mismatch(name, h),
+            Err(e) => error(e),
+        }
+    }
+
+    /// Like upload_from for async functions, limited but without
+    /// the context, the around interceptors or catching panics
+    #[cfg(feature = "async")]
+    pub(crate) async fn upload_async(
+        &self,
+        peer: Peer<'_>,
+        mut q: ByteQue,
+        rx: &mut aio::AsyncReceiver<'_>,
+    ) -> ByteQue {
+        let f = match self.find(peer.identity, &mut q) {
+            Ok((name, Handler::AsyncUpload(f))) => (name, f),
+            Ok((name, h)) => return mismatch(name, h),
+            Err(e) => return error(e),
+        };
+        let _permit = match self.limiter.acquire(&peer.call(f.0)) {
+            Ok(p) => p,
+            Err(e) => return error(e),
+        };
+        (f.1)(q, rx).await
+    }
+
+    /// Like upload_async, the Result<()> of the duplex function is returned
+    #[cfg(feature = "async")]
+    pub(crate) async fn duplex_async(
+        &self,
+        peer: Peer<'_>,
+        mut q: ByteQue,
+        d: &mut aio::AsyncDuplex<'_>,
+    ) -> ByteQue {
+        let f = match self.find(peer.identity, &mut q) {
+            Ok((name, Handler::AsyncDuplex(f))) => (name, f),
+            Ok((name, h)) => return mismatch(name, h),
+            Err(e) => return error(e),
+        };
+        let _permit = match self.limiter.acquire(&peer.call(f.0)) {
+            Ok(p) => p,
+            Err(e) => return error(e),
+        };
+        let mut r = ByteQue::new();
+        (f.1)(q, d).await.store(&mut r);
+        r
+    }
+}
+
+/// Whether a rule for the identity or for * matches the name
+fn permits(acl: &HashMap<String, Vec<String>>, identity: &str, name: &str) -> bool {
+    [identity, "*"]
+        .iter()
+        .filter_map(|i| acl.get(*i))
+        .any(|ps| ps.iter().any(|p| matches(p, name)))
+}
+
+/// A pattern ending with * matches the names starting with the rest
+pub(crate) fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => pattern == name,
+    }
+}
+
+/// The identity, or the address without its port if there is none
+pub(crate) fn caller<'a>(identity: &'a str, addr: &'a str) -> &'a str {
+    if !identity.is_empty() {
+        return identity;
+    }
+    match addr.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => host,
+        _ => addr,
+    }
+}
+
+impl<'a> Peer<'a> {
+    fn call(self, name: &'a str) -> Call<'a> {
+        Call {
+            name,
+            peer: self.addr,
+            identity: self.identity,
+        }
+    }
+}
+
+fn mismatch(name: &str, h: Handler) -> ByteQue {
+    error(format!("{} function is {} function", name, h.kind()))
+}
+
+fn error(e: String) -> ByteQue {
+    let mut r = ByteQue::new();
+    true.store(&mut r);
+    e.store(&mut r);
+    r
+}
+
+/// Registrar returned by Fun::service
+pub struct Service<'a> {
+    fun: &'a mut Fun,
+    namespace: String,
+}
+
+impl Service<'_> {
+    pub fn regist(&mut self, name: &str, f: fn(&mut ByteQue) -> ByteQue) {
+        self.fun.regist(&format!("{}.{}", self.namespace, name), f);
+    }
+
+    pub fn regist_idempotent(&mut self, name: &str, f: fn(&mut ByteQue) -> ByteQue) {
+        self.fun
+            .regist_idempotent(&format!("{}.{}", self.namespace, name), f);
+    }
+
+    pub fn regist_stream(&mut self, name: &str, f: fn(&mut ByteQue, &mut Sender) -> Result<()>) {
+        self.fun
+            .regist_stream(&format!("{}.{}", self.namespace, name), f);
+    }
+
+    pub fn regist_upload(&mut self, name: &str, f: fn(&mut ByteQue, &mut Receiver) -> ByteQue) {
+        self.fun
+            .regist_upload(&format!("{}.{}", self.namespace, name), f);
+    }
+
+    pub fn regist_duplex(&mut self, name: &str, f: fn(&mut ByteQue, &mut Duplex) -> Result<()>) {
+        self.fun
+            .regist_duplex(&format!("{}.{}", self.namespace, name), f);
+    }
+
+    #[cfg(feature = "async")]
+    pub fn regist_upload_async(&mut self, name: &str, f: aio::UploadFn) {
+        self.fun
+            .regist_upload_async(&format!("{}.{}", self.namespace, name), f);
+    }
+
+    #[cfg(feature = "async")]
+    pub fn regist_duplex_async(&mut self, name: &str, f: aio::DuplexFn) {
+        self.fun
+            .regist_duplex_async(&format!("{}.{}", self.namespace, name), f);
+    }
+
+    /// Nested namespace, "namespace.sub"
+    pub fn service(&mut self, namespace: &str) -> Service<'_> {
+        Service {
+            fun: self.fun,
+            namespace: format!("{}.{}", self.namespace, namespace),
         }
     }
 }
 
+#[macro_export]
+macro_rules! fun_id {
+    ($id:expr $(,$arg:expr)*) => {{
+        #[allow(unused_mut)]
+        let mut q = $crate::FunId::from($id).call();
+        $($arg.store(&mut q);)*
+        q
+    }};
+    ($id:expr, $($arg:expr,)*) => {
+        $crate::fun_id!($id $(,$arg)*)
+    }
+}
+
 #[macro_export]
 macro_rules! fun {
     ($name:expr $(,$arg:expr)*) => {{
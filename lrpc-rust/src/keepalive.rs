@@ -0,0 +1,195 @@
+//! Heartbeats keeping connections alive, and closing the dead ones
+//!
+//! An empty frame is a heartbeat, skipped by the side receiving it.
+//! With keepalive set, a side sends one whenever it has written nothing else
+//! for half the interval, and closes the connection once it has waited
+//! MISSES intervals for a frame without receiving anything.
+//! A client with keepalive reads on another thread, see Inbox,
+//! so it is always waiting and notices a dead server while idle.
+//! It tells the server its interval with a PING frame before its next call,
+//! the server then sends heartbeats at that interval whatever its own keepalive.
+
+use crate::{
+    buf::{recv_data, send_data},
+    link::Link,
+    val::ByteQue,
+};
+use std::{
+    io::{self, Read, Write},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, Receiver},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Intervals without a frame before the other side is taken for dead
+const MISSES: u32 = 3;
+
+/// The shortest interval, shorter ones are taken as this
+pub(crate) const MIN_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Which end of the link sends the heartbeats, and when it closes the link
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Side {
+    /// Closes the link once the server has sent heartbeats and goes silent.
+    /// The server only does once it got the PING, before that
+    /// a server without keepalive is silent while it runs a long call
+    Client,
+    /// Closes the link when the client is silent while no call runs
+    Server,
+    /// A server answering a PING, never closes the link
+    Answer,
+}
+
+/// When the bytes of a connection last went each way, in milliseconds
+pub(crate) struct Pulse {
+    start: Instant,
+    /// Since the last bytes read, or the read waiting for them started
+    heard: AtomicU64,
+    wrote: AtomicU64,
+    reading: AtomicBool,
+    /// A heartbeat was received
+    beats: AtomicBool,
+    stopped: AtomicBool,
+}
+
+impl Pulse {
+    pub(crate) fn new() -> Self {
+        Pulse {
+            start: Instant::now(),
+            heard: AtomicU64::new(0),
+            wrote: AtomicU64::new(0),
+            reading: AtomicBool::new(false),
+            beats: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+
+    fn since(&self, t: &AtomicU64) -> Duration {
+        Duration::from_millis(self.now().saturating_sub(t.load(Ordering::SeqCst)))
+    }
+
+    pub(crate) fn reading(&self, on: bool) {
+        self.heard.store(self.now(), Ordering::SeqCst);
+        self.reading.store(on, Ordering::SeqCst);
+    }
+
+    pub(crate) fn wrote(&self) {
+        self.wrote.store(self.now(), Ordering::SeqCst);
+    }
+
+    fn beat(&self) {
+        self.beats.store(true, Ordering::SeqCst);
+    }
+
+    /// The heartbeats stop, the connection is closed
+    pub(crate) fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Read the next frame that is not a heartbeat
+pub(crate) fn recv<R: Read + ?Sized>(r: &mut R) -> io::Result<ByteQue> {
+    loop {
+        let q = recv_data(r)?;
+        if !q.is_empty() {
+            return Ok(q);
+        }
+    }
+}
+
+/// Send heartbeats on the link on another thread until it is closed,
+/// closing it when the other side is silent too long, see Side
+pub(crate) fn keepalive(link: Link, interval: Duration, side: Side) {
+    let interval = interval.max(MIN_INTERVAL);
+    let half = interval / 2;
+    let mut link = link;
+    thread::spawn(move || loop {
+        thread::sleep(half);
+        let p = link.pulse();
+        if p.stopped.load(Ordering::SeqCst) {
+            return;
+        }
+        if side != Side::Answer
+            && p.reading.load(Ordering::SeqCst)
+            && p.since(&p.heard) >= interval * MISSES
+            && (side == Side::Server || p.beats.load(Ordering::SeqCst))
+        {
+            return link.close();
+        }
+        if p.since(&p.wrote) >= half && link.write_all(&send_data(ByteQue::new())).is_err() {
+            return;
+        }
+    });
+}
+
+/// Client side of a link with keepalive. Another thread reads the link,
+/// noting the heartbeats and passing the other frames on to be read here.
+/// Written bytes go to the link
+pub(crate) struct Inbox {
+    link: Link,
+    frames: Receiver<ByteQue>,
+    /// The frame being read, with its length
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl Inbox {
+    /// The reading thread ends when the link is closed
+    pub(crate) fn new(link: Link) -> Self {
+        let (tx, frames) = mpsc::channel();
+        let mut r = link.clone();
+        thread::spawn(move || {
+            while let Ok(q) = recv_data(&mut r) {
+                if q.is_empty() {
+                    r.pulse().beat();
+                } else if tx.send(q).is_err() {
+                    return;
+                }
+            }
+        });
+        Inbox {
+            link,
+            frames,
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl Read for Inbox {
+    /// Ends once the link is closed and the frames read before are taken
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.buf.len() {
+            match self.frames.recv() {
+                Ok(q) => self.buf = send_data(q),
+                Err(_) => return Ok(0),
+            }
+            self.pos = 0;
+        }
+        let n = buf.len().min(self.buf.len() - self.pos);
+        buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl Write for Inbox {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.link.write(buf)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.link.write_all(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.link.flush()
+    }
+}
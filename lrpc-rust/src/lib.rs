@@ -2,7 +2,7 @@
 //!
 //! # Examples
 //!
-//! ```no_run
+//! ```
 //! use lrpc::*;
 //!
 //! #[derive(CommonStore, Debug)]
@@ -33,13 +33,9 @@
 //!     srv_fun.regist("new_circle", new_circle);
 //!     srv_fun.regist("circle_area", circle_area);
 //!
-//!     //start service
-//!     std::thread::spawn(move || {
-//!         service(srv_fun, "0.0.0.0:9009");
-//!     });
-//!     std::thread::sleep(std::time::Duration::from_millis(10));
-//!
-//!     let mut conn = Connection::new("127.0.0.1:9009");
+//!     // in another process: service(srv_fun, "0.0.0.0:9009")
+//!     // and Connection::new("127.0.0.1:9009")
+//!     let mut conn = Connection::loopback(srv_fun);
 //!     let circle: Result<Circle> = conn.invoke(fun!("new_circle", Point(400, 300), 100));
 //!     if let Ok(circle) = circle {
 //!         println!("{:?}", circle);
@@ -53,12 +49,55 @@ mod val;
 pub use val::{ByteQue, Store};
 #[macro_use]
 mod fun;
-pub use fun::{Fun, Result};
+pub use fun::{Call, Fun, FunId, Result, Sender, Service};
 pub use lrpc_macros::{fmt_function, CommonStore};
+mod context;
+pub use context::{context, Context, Session};
+mod retry;
+pub use retry::Retry;
+mod limit;
+pub use limit::Limit;
+mod callback;
+pub use callback::callback;
+mod chan;
+pub use chan::{Duplex, Receiver, Upload};
+#[cfg(feature = "async")]
+mod aio;
+#[cfg(feature = "async")]
+pub use aio::{serve_async, AsyncDuplex, AsyncReceiver, AsyncUpload, BoxFuture};
+#[cfg(feature = "psk")]
+mod auth;
+mod frame;
+mod keepalive;
+mod link;
+mod pubsub;
+pub use pubsub::{Hub, SlowConsumer};
 mod buf;
-pub use buf::{send_data, RecvBuf};
+pub use buf::{recv_data, send_data, RecvBuf};
+mod transport;
+pub use transport::{service_on, Listener, Transport};
+mod mem;
+pub use mem::{pipe, Pipe};
+mod stdio;
+pub use stdio::serve_stdio;
 mod tcp;
-pub use tcp::{service, Connection};
+pub use tcp::{service, Batch, Connection, Stream};
+mod pool;
+pub use pool::{Pool, Pooled};
+mod breaker;
+pub use breaker::{Breaker, Circuit};
+mod balance;
+pub use balance::{Balancer, Strategy};
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "tls")]
+pub use rustls;
+#[cfg(feature = "tls")]
+pub use tls::{service_tls, tls_client_config, tls_server_config, Tls, TlsListener};
+#[cfg(unix)]
+mod unix;
+#[cfg(unix)]
+pub use unix::{service_unix, service_unix_mode};
 
 #[cfg(test)]
 mod tests;
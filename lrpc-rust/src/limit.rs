@@ -0,0 +1,183 @@
+//! Rate and concurrency limits of the functions
+
+use crate::fun::{caller, matches, Call, Result};
+use std::{collections::HashMap, sync::Mutex, time::Instant};
+
+/// Limits of the functions matching a pattern, added with Fun::limit.
+/// Each function is limited on its own
+#[derive(Clone, Copy, Debug)]
+pub struct Limit {
+    rate: f64,
+    burst: f64,
+    in_flight: usize,
+    per_peer: bool,
+}
+
+impl Default for Limit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Limit {
+    /// No limit until one is set
+    pub fn new() -> Self {
+        Limit {
+            rate: f64::INFINITY,
+            burst: f64::INFINITY,
+            in_flight: usize::MAX,
+            per_peer: false,
+        }
+    }
+
+    /// At most per_second calls, up to burst calls at once
+    /// after a quiet period (token bucket)
+    pub fn rate(mut self, per_second: f64, burst: u32) -> Self {
+        self.rate = per_second;
+        self.burst = burst as f64;
+        self
+    }
+
+    /// At most n calls running at the same time
+    pub fn in_flight(mut self, n: usize) -> Self {
+        self.in_flight = n;
+        self
+    }
+
+    /// The limits apply to each caller, told apart by its identity
+    /// or the host of its address if it has none
+    pub fn per_peer(mut self) -> Self {
+        self.per_peer = true;
+        self
+    }
+}
+
+struct State {
+    tokens: f64,
+    at: Instant,
+    running: usize,
+}
+
+struct Rule {
+    pattern: String,
+    limit: Limit,
+    /// By function name and peer
+    states: Mutex<HashMap<(String, String), State>>,
+}
+
+impl Rule {
+    fn key(&self, call: &Call) -> (String, String) {
+        // the connections of a host share its allowance
+        let peer = if self.limit.per_peer {
+            caller(call.identity, call.peer)
+        } else {
+            ""
+        };
+        (call.name.to_string(), peer.to_string())
+    }
+
+    /// The state of the key, its tokens refilled up to now
+    fn state<'s>(
+        &self,
+        states: &'s mut HashMap<(String, String), State>,
+        key: &(String, String),
+        now: Instant,
+    ) -> &'s mut State {
+        let l = &self.limit;
+        if states.len() > 1024 {
+            // forget the peers that are back to their full allowance
+            states.retain(|_, s| {
+                s.running > 0
+                    || s.tokens + now.duration_since(s.at).as_secs_f64() * l.rate < l.burst
+            });
+        }
+        let s = states.entry(key.clone()).or_insert(State {
+            tokens: l.burst,
+            at: now,
+            running: 0,
+        });
+        s.tokens = l
+            .burst
+            .min(s.tokens + now.duration_since(s.at).as_secs_f64() * l.rate);
+        s.at = now;
+        s
+    }
+
+    fn check(&self, s: &State, name: &str) -> Result<()> {
+        if s.tokens < 1.0 {
+            return Err(format!(
+                "resource exhausted: {} function is called too often",
+                name
+            ));
+        }
+        if s.running >= self.limit.in_flight {
+            return Err(format!(
+                "resource exhausted: {} function has too many calls running",
+                name
+            ));
+        }
+        Ok(())
+    }
+
+    fn release(&self, key: &(String, String)) {
+        if let Some(s) = self.states.lock().unwrap().get_mut(key) {
+            s.running -= 1;
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Limiter {
+    rules: Vec<Rule>,
+}
+
+/// A call let through, it stops counting as running when dropped
+pub(crate) struct Permit<'a> {
+    held: Vec<(&'a Rule, (String, String))>,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        for (rule, key) in &self.held {
+            rule.release(key);
+        }
+    }
+}
+
+impl Limiter {
+    pub(crate) fn add(&mut self, pattern: &str, limit: Limit) {
+        self.rules.push(Rule {
+            pattern: pattern.to_string(),
+            limit,
+            states: Mutex::new(HashMap::new()),
+        });
+    }
+
+    /// Count the call against the limits it falls under,
+    /// none of them counts it unless all let it through
+    pub(crate) fn acquire(&self, call: &Call) -> Result<Permit<'_>> {
+        let held: Vec<(&Rule, (String, String))> = self
+            .rules
+            .iter()
+            .filter(|rule| matches(&rule.pattern, call.name))
+            .map(|rule| (rule, rule.key(call)))
+            .collect();
+        // locked in the order of the rules, as every call does
+        let mut locked: Vec<_> = held
+            .iter()
+            .map(|(rule, _)| rule.states.lock().unwrap())
+            .collect();
+        let now = Instant::now();
+        for ((rule, key), states) in held.iter().zip(locked.iter_mut()) {
+            rule.check(rule.state(states, key, now), call.name)?;
+        }
+        for ((_, key), states) in held.iter().zip(locked.iter_mut()) {
+            if let Some(s) = states.get_mut(key) {
+                s.tokens -= 1.0;
+                s.running += 1;
+            }
+        }
+        drop(locked);
+        Ok(Permit { held })
+    }
+}
@@ -0,0 +1,158 @@
+//! One connection as seen by the server
+
+#[cfg(feature = "psk")]
+use crate::auth;
+use crate::{
+    callback,
+    context::{Context, Session},
+    frame,
+    fun::Fun,
+    keepalive::{self, Pulse, Side},
+    pubsub::{Hub, Subscriber},
+};
+use std::{
+    io::{self, Read, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+/// Both halves of a connection, shared by the threads using it.
+/// A frame written with write_all is never interleaved with another.
+#[derive(Clone)]
+pub(crate) struct Link {
+    r: Arc<Mutex<Box<dyn Read + Send>>>,
+    w: Arc<Mutex<Box<dyn Write + Send>>>,
+    close: Arc<dyn Fn() + Send + Sync>,
+    pulse: Arc<Pulse>,
+}
+
+impl Link {
+    pub(crate) fn new<R, W, C>(r: R, w: W, close: C) -> Self
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+        C: Fn() + Send + Sync + 'static,
+    {
+        Link {
+            r: Arc::new(Mutex::new(Box::new(r))),
+            w: Arc::new(Mutex::new(Box::new(w))),
+            close: Arc::new(close),
+            pulse: Arc::new(Pulse::new()),
+        }
+    }
+
+    /// Close the connection, a thread blocked reading it returns
+    pub(crate) fn close(&self) {
+        self.pulse.stop();
+        (self.close)()
+    }
+
+    pub(crate) fn pulse(&self) -> &Pulse {
+        &self.pulse
+    }
+}
+
+impl Read for Link {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut r = self.r.lock().unwrap();
+        self.pulse.reading(true);
+        let n = r.read(buf);
+        self.pulse.reading(false);
+        n
+    }
+}
+
+impl Write for Link {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.w.lock().unwrap().write(buf)?;
+        self.pulse.wrote();
+        Ok(n)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.w.lock().unwrap().write_all(buf)?;
+        self.pulse.wrote();
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.w.lock().unwrap().flush()
+    }
+}
+
+/// State of one connection, dropped when it ends
+pub(crate) struct Conn {
+    pub(crate) link: Link,
+    pub(crate) peer: String,
+    pub(crate) identity: String,
+    pub(crate) id: u64,
+    /// Deadline of the call being dispatched
+    pub(crate) deadline: Option<Instant>,
+    pub(crate) session: Session,
+    pub(crate) subscriber: Option<(Hub, Arc<Subscriber>)>,
+    /// Heartbeats are sent at the interval of the caller's PING
+    pub(crate) answering: bool,
+}
+
+impl Drop for Conn {
+    fn drop(&mut self) {
+        if let Some((hub, s)) = self.subscriber.take() {
+            hub.leave(&s);
+        }
+        callback::set_caller(None);
+        self.link.close();
+    }
+}
+
+/// Numbers the connections served
+pub(crate) static CONNECTIONS: AtomicU64 = AtomicU64::new(1);
+
+/// Serve one connection on this thread until it ends
+pub(crate) fn serve(fun: &Fun, link: Link, peer: String, identity: String) {
+    callback::set_caller(Some(Arc::new(Mutex::new(Box::new(link.clone())))));
+    let mut conn = Conn {
+        link,
+        peer,
+        identity,
+        id: CONNECTIONS.fetch_add(1, Ordering::Relaxed),
+        deadline: None,
+        session: Session::default(),
+        subscriber: None,
+        answering: false,
+    };
+    run(fun, &mut conn);
+    conn.link.close();
+}
+
+/// Everything serve does on a connection before closing it
+fn run(fun: &Fun, conn: &mut Conn) {
+    if let Some(interval) = fun.keepalive_interval() {
+        keepalive::keepalive(conn.link.clone(), interval, Side::Server);
+    }
+    #[cfg(feature = "psk")]
+    if let Some(keys) = fun.keys() {
+        match auth::accept(&mut conn.link, keys) {
+            Ok(id) => conn.identity = format!("psk:{}", id),
+            Err(_) => return,
+        }
+    }
+    let context = Context {
+        peer: conn.peer.clone(),
+        identity: conn.identity.clone(),
+        connection: conn.id,
+        session: conn.session.clone(),
+        ..Context::default()
+    };
+    if !fun.connected(&context) {
+        return;
+    }
+    while let Ok(q) = keepalive::recv(&mut conn.link) {
+        if frame::dispatch(fun, conn, q).is_err() {
+            break;
+        }
+    }
+    fun.disconnected(&context);
+}
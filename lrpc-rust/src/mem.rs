@@ -0,0 +1,182 @@
+//! Streams connecting a client and a service in the same process
+//!
+//! # Examples
+//!
+//! ```
+//! use lrpc::*;
+//!
+//! #[fmt_function]
+//! fn add(a: i32, b: i32) -> i32 {
+//!     a + b
+//! }
+//!
+//! let mut srv_fun = Fun::new();
+//! srv_fun.regist("add", add);
+//! let mut conn = Connection::loopback(srv_fun);
+//! let sum: Result<i32> = conn.invoke(fun!("add", 1, 2));
+//! assert_eq!(sum, Ok(3));
+//! ```
+
+use crate::transport::Transport;
+#[cfg(feature = "async")]
+use futures_io::{AsyncRead, AsyncWrite};
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+};
+#[cfg(feature = "async")]
+use std::{
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+/// Bytes going one way
+#[derive(Default)]
+struct Half {
+    bytes: Mutex<(VecDeque<u8>, bool)>,
+    ready: Condvar,
+    /// async readers waiting for bytes
+    #[cfg(feature = "async")]
+    wakers: Mutex<Vec<Waker>>,
+}
+
+impl Half {
+    fn close(&self) {
+        self.bytes.lock().unwrap().1 = true;
+        self.wake();
+    }
+
+    /// Bytes came or the half is closed
+    fn wake(&self) {
+        self.ready.notify_all();
+        #[cfg(feature = "async")]
+        for w in self.wakers.lock().unwrap().drain(..) {
+            w.wake();
+        }
+    }
+}
+
+/// One end of an in-memory stream created by pipe.
+/// It is closed when all its handles are dropped
+pub struct Pipe {
+    rx: Arc<Half>,
+    tx: Arc<Half>,
+    handles: Arc<AtomicUsize>,
+}
+
+/// Both ends of an in-memory stream,
+/// what is written to one is read from the other
+pub fn pipe() -> (Pipe, Pipe) {
+    let a = Arc::new(Half::default());
+    let b = Arc::new(Half::default());
+    let end = |rx, tx| Pipe {
+        rx,
+        tx,
+        handles: Arc::new(AtomicUsize::new(1)),
+    };
+    (end(a.clone(), b.clone()), end(b, a))
+}
+
+impl Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut q = self.rx.bytes.lock().unwrap();
+        while q.0.is_empty() && !q.1 {
+            q = self.rx.ready.wait(q).unwrap();
+        }
+        let n = buf.len().min(q.0.len());
+        for (b, v) in buf.iter_mut().zip(q.0.drain(..n)) {
+            *b = v;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut q = self.tx.bytes.lock().unwrap();
+        if q.1 {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        q.0.extend(buf);
+        self.tx.wake();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads wait without blocking the thread, see serve_async
+#[cfg(feature = "async")]
+impl AsyncRead for Pipe {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut q = self.rx.bytes.lock().unwrap();
+        if q.0.is_empty() && !q.1 {
+            // registered while the bytes are locked, a write wakes it after
+            self.rx.wakers.lock().unwrap().push(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = buf.len().min(q.0.len());
+        for (b, v) in buf.iter_mut().zip(q.0.drain(..n)) {
+            *b = v;
+        }
+        Poll::Ready(Ok(n))
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncWrite for Pipe {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(self.get_mut().write(buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Transport::close(&*self);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Transport for Pipe {
+    fn try_clone(&self) -> io::Result<Self> {
+        self.handles.fetch_add(1, Ordering::SeqCst);
+        Ok(Pipe {
+            rx: self.rx.clone(),
+            tx: self.tx.clone(),
+            handles: self.handles.clone(),
+        })
+    }
+
+    fn close(&self) {
+        self.rx.close();
+        self.tx.close();
+    }
+
+    fn peer(&self) -> String {
+        String::from("loopback")
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        if self.handles.fetch_sub(1, Ordering::SeqCst) == 1 {
+            Transport::close(self);
+        }
+    }
+}
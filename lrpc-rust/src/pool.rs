@@ -0,0 +1,310 @@
+//! Connections shared by the threads of a client
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use lrpc::*;
+//! use std::time::Duration;
+//!
+//! let pool = Pool::new("127.0.0.1:9009")
+//!     .min(2)
+//!     .max(16)
+//!     .idle_timeout(Duration::from_secs(30));
+//! std::thread::scope(|s| {
+//!     for i in 0..4 {
+//!         let pool = &pool;
+//!         s.spawn(move || pool.invoke::<i32>(fun!("double", i)));
+//!     }
+//! });
+//! ```
+
+use crate::{
+    breaker::Breaker,
+    fun::Result,
+    tcp::Connection,
+    val::{ByteQue, Store},
+};
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+type Connect = Box<dyn Fn() -> Result<Connection> + Send + Sync>;
+type Check = Box<dyn Fn(&mut Connection) -> bool + Send + Sync>;
+
+struct State {
+    /// Connections not in use, the most recently used last
+    idle: Vec<(Connection, Instant)>,
+    /// Connections open, in use or idle
+    open: usize,
+}
+
+impl State {
+    /// Take out the connections idle for longer than timeout, keeping min open
+    fn evict(&mut self, min: usize, timeout: Duration) -> Vec<Connection> {
+        let now = Instant::now();
+        let mut closed = Vec::new();
+        // the oldest are first
+        while self.open > min && self.idle.first().is_some_and(|(_, at)| now - *at > timeout) {
+            closed.push(self.idle.remove(0).0);
+            self.open -= 1;
+        }
+        closed
+    }
+}
+
+/// The state shared with the thread closing idle connections
+struct Shared {
+    state: Mutex<State>,
+    returned: Condvar,
+}
+
+/// A thread-safe set of connections, each call checks one out.
+/// Broken connections are dropped when they are given back
+pub struct Pool {
+    connect: Connect,
+    check: Option<Check>,
+    breaker: Option<Breaker>,
+    min: usize,
+    max: usize,
+    idle_timeout: Option<Duration>,
+    shared: Arc<Shared>,
+    /// The thread closing idle connections was started
+    reaping: AtomicBool,
+}
+
+impl Pool {
+    /// Connections to the tcp service at addr, at most 8 of them
+    pub fn new(addr: &str) -> Self {
+        let addr = addr.to_string();
+        Pool::with(move || Connection::dial(&addr))
+    }
+
+    /// Connections made by connect
+    pub fn with<F>(connect: F) -> Self
+    where
+        F: Fn() -> Result<Connection> + Send + Sync + 'static,
+    {
+        Pool {
+            connect: Box::new(connect),
+            check: None,
+            breaker: None,
+            min: 0,
+            max: 8,
+            idle_timeout: None,
+            shared: Arc::new(Shared {
+                state: Mutex::new(State {
+                    idle: Vec::new(),
+                    open: 0,
+                }),
+                returned: Condvar::new(),
+            }),
+            reaping: AtomicBool::new(false),
+        }
+    }
+
+    /// Connections kept open even when idle, opened by the first call
+    pub fn min(mut self, n: usize) -> Self {
+        self.min = n;
+        self
+    }
+
+    /// Calls wait for a connection once n are in use
+    pub fn max(mut self, n: usize) -> Self {
+        self.max = n.max(1);
+        self
+    }
+
+    /// Close connections idle for longer, keeping min of them.
+    /// A thread started with the first idle connection closes them,
+    /// it ends after the pool is dropped
+    pub fn idle_timeout(mut self, d: Duration) -> Self {
+        self.idle_timeout = Some(d);
+        self
+    }
+
+    /// Run on an idle connection before it is checked out,
+    /// it is dropped if this returns false
+    pub fn health_check<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&mut Connection) -> bool + Send + Sync + 'static,
+    {
+        self.check = Some(Box::new(f));
+        self
+    }
+
+    /// Set the breaker on the connections, no connection is made
+    /// while it is open and those that cannot be made count as failures
+    pub fn breaker(mut self, breaker: Breaker) -> Self {
+        self.breaker = Some(breaker);
+        self
+    }
+
+    /// Number of connections open and of those idle
+    pub fn size(&self) -> (usize, usize) {
+        let s = self.shared.state.lock().unwrap();
+        (s.open, s.idle.len())
+    }
+
+    /// Check out a connection, given back when the returned guard is dropped
+    pub fn get(&self) -> Result<Pooled<'_>> {
+        if let Some(b) = &self.breaker {
+            b.check()?;
+        }
+        if self.size().0 < self.min {
+            self.fill();
+        }
+        let mut s = self.shared.state.lock().unwrap();
+        loop {
+            if let Some(t) = self.idle_timeout {
+                let closed = s.evict(self.min, t);
+                if !closed.is_empty() {
+                    drop(s);
+                    drop(closed);
+                    s = self.shared.state.lock().unwrap();
+                }
+            }
+            if let Some((mut conn, _)) = s.idle.pop() {
+                drop(s);
+                if self.check.as_ref().map_or(true, |f| f(&mut conn)) {
+                    return Ok(Pooled {
+                        pool: self,
+                        conn: Some(conn),
+                    });
+                }
+                s = self.shared.state.lock().unwrap();
+                s.open -= 1;
+                continue;
+            }
+            if s.open < self.max {
+                s.open += 1;
+                drop(s);
+                return match self.connect() {
+                    Ok(conn) => Ok(Pooled {
+                        pool: self,
+                        conn: Some(conn),
+                    }),
+                    Err(e) => {
+                        self.closed();
+                        Err(e)
+                    }
+                };
+            }
+            s = self.shared.returned.wait(s).unwrap();
+        }
+    }
+
+    /// Invoke on a connection checked out for the call
+    pub fn invoke<T: Store>(&self, fun: ByteQue) -> Result<T> {
+        self.get()?.invoke(fun)
+    }
+
+    /// Open connections up to min
+    fn fill(&self) {
+        loop {
+            {
+                let mut s = self.shared.state.lock().unwrap();
+                if s.open >= self.min {
+                    return;
+                }
+                s.open += 1;
+            }
+            match self.connect() {
+                Ok(conn) => self.put(conn),
+                Err(_) => return self.closed(),
+            }
+        }
+    }
+
+    fn connect(&self) -> Result<Connection> {
+        let breaker = match &self.breaker {
+            Some(b) => b,
+            None => return (self.connect)(),
+        };
+        match (self.connect)() {
+            Ok(mut conn) => {
+                conn.breaker(breaker.clone());
+                Ok(conn)
+            }
+            Err(e) => {
+                breaker.record(false);
+                Err(e)
+            }
+        }
+    }
+
+    /// Close the idle connections on another thread
+    /// every half timeout, until the pool is dropped
+    fn reap(&self, timeout: Duration) {
+        if self.reaping.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let shared: Weak<Shared> = Arc::downgrade(&self.shared);
+        let (min, every) = (self.min, (timeout / 2).max(Duration::from_millis(10)));
+        thread::spawn(move || loop {
+            thread::sleep(every);
+            let shared = match shared.upgrade() {
+                Some(s) => s,
+                None => return,
+            };
+            // closed once the lock is released
+            let closed = shared.state.lock().unwrap().evict(min, timeout);
+            if !closed.is_empty() {
+                shared.returned.notify_all();
+            }
+            drop(closed);
+        });
+    }
+
+    fn put(&self, conn: Connection) {
+        let mut s = self.shared.state.lock().unwrap();
+        if conn.is_broken() {
+            s.open -= 1;
+        } else {
+            s.idle.push((conn, Instant::now()));
+        }
+        self.shared.returned.notify_one();
+        drop(s);
+        if let Some(t) = self.idle_timeout {
+            self.reap(t);
+        }
+    }
+
+    fn closed(&self) {
+        self.shared.state.lock().unwrap().open -= 1;
+        self.shared.returned.notify_one();
+    }
+}
+
+/// A connection checked out of a Pool
+pub struct Pooled<'a> {
+    pool: &'a Pool,
+    conn: Option<Connection>,
+}
+
+impl Deref for Pooled<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for Pooled<'_> {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.conn.as_mut().unwrap()
+    }
+}
+
+impl Drop for Pooled<'_> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.put(conn);
+        }
+    }
+}
@@ -0,0 +1,186 @@
+//! Publish events to the connections subscribed to a topic
+//!
+//! # Examples
+//!
+//! ```
+//! use lrpc::*;
+//!
+//! let hub = Hub::new(64, SlowConsumer::DropOldest);
+//! let mut srv_fun = Fun::new();
+//! srv_fun.pubsub(hub.clone());
+//! let mut conn = Connection::loopback(srv_fun);
+//! conn.subscribe("prices").unwrap();
+//! hub.publish("prices", &101.5f64);
+//! let (topic, price): (String, f64) = conn.event().unwrap();
+//! assert_eq!((topic.as_str(), price), ("prices", 101.5));
+//! ```
+
+use crate::{
+    buf::send_data,
+    frame,
+    link::Link,
+    val::{ByteQue, Store},
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::Write,
+    sync::{Arc, Condvar, Mutex},
+    thread,
+};
+
+/// What happens when a subscriber's queue is full
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SlowConsumer {
+    /// Discard the oldest queued event to make room
+    DropOldest,
+    /// Discard the event being published
+    DropNewest,
+    /// Close the subscriber's connection
+    Disconnect,
+}
+
+struct Queue {
+    events: VecDeque<Arc<Vec<u8>>>,
+    closed: bool,
+}
+
+/// A subscribed connection, its events are written by its own thread
+pub(crate) struct Subscriber {
+    queue: Mutex<Queue>,
+    ready: Condvar,
+}
+
+impl Subscriber {
+    fn close(&self) {
+        self.queue.lock().unwrap().closed = true;
+        self.ready.notify_one();
+    }
+
+    fn run(&self, mut link: Link) {
+        loop {
+            let e = {
+                let mut q = self.queue.lock().unwrap();
+                while q.events.is_empty() && !q.closed {
+                    q = self.ready.wait(q).unwrap();
+                }
+                if q.closed {
+                    return;
+                }
+                q.events.pop_front().unwrap()
+            };
+            if link.write_all(&e).is_err() {
+                self.close();
+                return;
+            }
+        }
+    }
+}
+
+struct Inner {
+    topics: Mutex<HashMap<String, Vec<Arc<Subscriber>>>>,
+    capacity: usize,
+    policy: SlowConsumer,
+}
+
+/// Topics and their subscribers, cloning it shares them
+#[derive(Clone)]
+pub struct Hub(Arc<Inner>);
+
+impl Hub {
+    /// capacity is the number of events queued for each subscriber
+    pub fn new(capacity: usize, policy: SlowConsumer) -> Self {
+        Hub(Arc::new(Inner {
+            topics: Mutex::new(HashMap::new()),
+            capacity,
+            policy,
+        }))
+    }
+
+    /// Queue the event for every subscriber of the topic,
+    /// returns the number of subscribers it was queued for
+    pub fn publish<T: Store>(&self, topic: &str, event: &T) -> usize {
+        let mut q = ByteQue::new();
+        topic.to_string().store(&mut q);
+        event.store(&mut q);
+        let e = Arc::new(send_data(frame::push(frame::PUBLISH, q)));
+        let topics = self.0.topics.lock().unwrap();
+        let mut n = 0;
+        for s in topics.get(topic).into_iter().flatten() {
+            let mut q = s.queue.lock().unwrap();
+            if q.closed {
+                continue;
+            }
+            if q.events.len() >= self.0.capacity {
+                match self.0.policy {
+                    SlowConsumer::DropOldest => {
+                        q.events.pop_front();
+                    }
+                    SlowConsumer::DropNewest => continue,
+                    SlowConsumer::Disconnect => {
+                        q.closed = true;
+                        s.ready.notify_one();
+                        continue;
+                    }
+                }
+            }
+            q.events.push_back(e.clone());
+            s.ready.notify_one();
+            n += 1;
+        }
+        n
+    }
+
+    /// Number of subscribers of the topic
+    pub fn subscribers(&self, topic: &str) -> usize {
+        match self.0.topics.lock().unwrap().get(topic) {
+            Some(v) => v.len(),
+            None => 0,
+        }
+    }
+
+    /// A subscriber writing to the link on its own thread,
+    /// the link is closed if the subscriber is disconnected as a slow consumer
+    pub(crate) fn join(&self, link: Link) -> Arc<Subscriber> {
+        let s = Arc::new(Subscriber {
+            queue: Mutex::new(Queue {
+                events: VecDeque::new(),
+                closed: false,
+            }),
+            ready: Condvar::new(),
+        });
+        let t = s.clone();
+        thread::spawn(move || {
+            t.run(link.clone());
+            link.close();
+        });
+        s
+    }
+
+    pub(crate) fn subscribe(&self, topic: &str, s: &Arc<Subscriber>) {
+        let mut topics = self.0.topics.lock().unwrap();
+        let v = topics.entry(topic.to_string()).or_default();
+        if !v.iter().any(|x| Arc::ptr_eq(x, s)) {
+            v.push(s.clone());
+        }
+    }
+
+    pub(crate) fn unsubscribe(&self, topic: &str, s: &Arc<Subscriber>) {
+        let mut topics = self.0.topics.lock().unwrap();
+        if let Some(v) = topics.get_mut(topic) {
+            v.retain(|x| !Arc::ptr_eq(x, s));
+            if v.is_empty() {
+                topics.remove(topic);
+            }
+        }
+    }
+
+    /// Remove the subscriber from all topics and stop its thread
+    pub(crate) fn leave(&self, s: &Arc<Subscriber>) {
+        let mut topics = self.0.topics.lock().unwrap();
+        for v in topics.values_mut() {
+            v.retain(|x| !Arc::ptr_eq(x, s));
+        }
+        topics.retain(|_, v| !v.is_empty());
+        s.close();
+    }
+}
@@ -0,0 +1,136 @@
+//! Retrying calls after the connection is lost
+//!
+//! A call made with a Retry policy carries a key and the number of its
+//! attempt. The server keeps the replies by caller and key for a while,
+//! the caller being its identity or else its host. A retried call
+//! whose first attempt completed gets that reply without running again.
+//! Otherwise only an idempotent function runs again.
+
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::{BuildHasher, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// How Connection::invoke retries, set with Connection::retry
+#[derive(Clone, Copy, Debug)]
+pub struct Retry {
+    pub(crate) attempts: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl Retry {
+    /// Make the call up to attempts times,
+    /// waiting 10ms before the second and twice as long before each next one
+    pub fn new(attempts: u32) -> Self {
+        Retry {
+            attempts,
+            backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+
+    /// The first wait and the longest
+    pub fn backoff(mut self, first: Duration, max: Duration) -> Self {
+        self.backoff = first;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Wait before the attempt, counted from 1 for the first retry
+    pub(crate) fn wait(&self, attempt: u32) {
+        let factor = 1u32.checked_shl(attempt - 1).unwrap_or(u32::MAX);
+        thread::sleep(self.backoff.saturating_mul(factor).min(self.max_backoff));
+    }
+}
+
+/// A new key for a call, unlikely to be used by another caller.
+/// Not a secret, a caller can make calls with the key of another
+pub(crate) fn key() -> u64 {
+    static COUNT: AtomicU64 = AtomicU64::new(0);
+    let mut h = RandomState::new().build_hasher();
+    h.write_u64(COUNT.fetch_add(1, Ordering::Relaxed));
+    h.write_u32(std::process::id());
+    if let Ok(t) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        h.write_u128(t.as_nanos());
+    }
+    h.finish()
+}
+
+/// How long replies are kept and how many
+const KEEP: Duration = Duration::from_secs(60);
+const CAPACITY: usize = 4096;
+
+/// The reply of a call made with a key, None while it runs
+struct Kept {
+    at: Instant,
+    reply: Option<Vec<u8>>,
+}
+
+/// Who made the call and its key
+pub(crate) type Key = (String, u64);
+
+/// Replies of the calls made with a key
+#[derive(Default)]
+pub(crate) struct Dedup {
+    replies: Mutex<HashMap<Key, Kept>>,
+    done: Condvar,
+}
+
+pub(crate) enum Seen {
+    /// The reply of an attempt that completed
+    Reply(Vec<u8>),
+    /// No attempt is known, this one is now running
+    New,
+}
+
+impl Dedup {
+    /// Look up the key, waiting for an attempt that is still running
+    pub(crate) fn begin(&self, key: &Key) -> Seen {
+        let mut replies = self.replies.lock().unwrap();
+        loop {
+            match replies.get(key) {
+                Some(Kept { reply: Some(r), .. }) => return Seen::Reply(r.clone()),
+                Some(_) => replies = self.done.wait(replies).unwrap(),
+                None => break,
+            }
+        }
+        let now = Instant::now();
+        if replies.len() >= CAPACITY {
+            replies.retain(|_, k| k.reply.is_none() || now.duration_since(k.at) < KEEP);
+        }
+        if replies.len() >= CAPACITY {
+            let oldest = replies
+                .iter()
+                .filter(|(_, k)| k.reply.is_some())
+                .min_by_key(|(_, k)| k.at)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                replies.remove(&oldest);
+            }
+        }
+        replies.insert(
+            key.clone(),
+            Kept {
+                at: now,
+                reply: None,
+            },
+        );
+        Seen::New
+    }
+
+    pub(crate) fn end(&self, key: Key, reply: &[u8]) {
+        let kept = Kept {
+            at: Instant::now(),
+            reply: Some(reply.to_vec()),
+        };
+        self.replies.lock().unwrap().insert(key, kept);
+        self.done.notify_all();
+    }
+}
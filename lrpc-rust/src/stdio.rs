@@ -0,0 +1,99 @@
+//! Call functions of a child process over its stdin and stdout
+
+use crate::{
+    fun::Fun,
+    link::{serve, Link},
+};
+use std::{
+    io::{self, Read, Write},
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How long a dropped Process is given to exit before it is killed
+const EXIT_WAIT: Duration = Duration::from_secs(1);
+
+/// Serve the functions to the parent process over stdin and stdout,
+/// returns when the parent closes stdin.
+/// The functions must not print to stdout
+pub fn serve_stdio(srv_fun: Fun) {
+    let link = Link::new(io::stdin(), Flushed(io::stdout()), || ());
+    serve(&srv_fun, link, String::from("stdio"), String::new());
+}
+
+/// Stdout is buffered, every frame is flushed
+struct Flushed<W>(W);
+
+impl<W: Write> Write for Flushed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.0.write_all(buf)?;
+        self.0.flush()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// A child process serving with serve_stdio
+pub(crate) struct Process {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    stdout: ChildStdout,
+}
+
+impl Process {
+    pub(crate) fn spawn(cmd: &mut Command) -> io::Result<Self> {
+        let mut child = cmd.stdin(Stdio::piped()).stdout(Stdio::piped()).spawn()?;
+        let stdin = child.stdin.take();
+        match child.stdout.take() {
+            Some(stdout) => Ok(Process {
+                child,
+                stdin,
+                stdout,
+            }),
+            None => Err(io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+}
+
+impl Read for Process {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stdout.read(buf)
+    }
+}
+
+impl Write for Process {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.stdin {
+            Some(w) => w.write(buf),
+            None => Err(io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Process {
+    /// Closing stdin tells the child to exit, wait for it
+    /// and kill it if it is still running after EXIT_WAIT
+    fn drop(&mut self) {
+        self.stdin.take();
+        let start = Instant::now();
+        while let Ok(None) = self.child.try_wait() {
+            if start.elapsed() >= EXIT_WAIT {
+                let _ = self.child.kill();
+                let _ = self.child.wait();
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
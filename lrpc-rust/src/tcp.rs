@@ -1,55 +1,253 @@
+#[cfg(feature = "psk")]
+use crate::auth;
 use crate::{
-    buf::{send_data, RecvBuf},
+    breaker::Breaker,
+    buf::send_data,
+    chan::{Chan, Duplex, Io, Upload},
+    frame,
     fun::{Fun, Result},
+    keepalive::{self, Inbox, Side},
+    link::Link,
+    mem::pipe,
+    retry::{self, Retry},
+    stdio::Process,
+    transport::{self, serve_stream, service_on, Listener, Transport},
     val::{ByteQue, Store},
 };
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
 use std::{
-    io::{Read, Write},
+    collections::VecDeque,
+    io::{self, ErrorKind, Read, Write},
+    marker::PhantomData,
     net::{Shutdown, TcpListener, TcpStream},
-    sync::Arc,
+    process::Command,
     thread,
+    time::Duration,
 };
 
 /// Use tcp in the standard library to receive data,
 /// call the function with Fun,
 /// this is a blocking function
 pub fn service(srv_fun: Fun, addr: &str) {
-    let srv_fun = Arc::new(srv_fun);
-    let listener = TcpListener::bind(addr).unwrap();
-    for stream in listener.incoming() {
-        if let Ok(mut stream) = stream {
-            let srv_fun = srv_fun.clone();
-            thread::spawn(move || {
-                let mut buf = [0u8; 1024];
-                loop {
-                    let mut recv = RecvBuf::new();
-                    loop {
-                        match recv.size() {
-                            Some(s) if s == recv.len() => break,
-                            _ => match stream.read(&mut buf) {
-                                Ok(l) if l > 0 => recv.append(&buf[..l]),
-                                _ => match stream.shutdown(Shutdown::Both) {
-                                    _ => return,
-                                },
-                            },
-                        }
-                    }
-                    if let Err(_) = stream.write_all(&send_data(srv_fun.invoke(&mut recv.into()))) {
-                        match stream.shutdown(Shutdown::Both) {
-                            _ => return,
-                        }
-                    }
-                }
-            });
+    service_on(srv_fun, TcpListener::bind(addr).unwrap())
+}
+
+impl Transport for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn close(&self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+
+    fn peer(&self) -> String {
+        match self.peer_addr() {
+            Ok(a) => a.to_string(),
+            Err(_) => String::new(),
         }
     }
 }
 
-pub struct Connection(TcpStream);
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    fn accept(&self) -> Option<TcpStream> {
+        self.incoming().flatten().next()
+    }
+}
+
+pub struct Connection {
+    stream: Box<dyn Io + Send>,
+    callbacks: Option<Fun>,
+    events: VecDeque<ByteQue>,
+    timeout: Option<Duration>,
+    redial: Option<Redial>,
+    retry: Option<Retry>,
+    #[cfg(feature = "psk")]
+    psk: Option<(String, Vec<u8>)>,
+    breaker: Option<Breaker>,
+    /// The stream shared with the keepalive thread, if made from a Transport
+    link: Option<Link>,
+    keepalive: Option<Duration>,
+    /// The server was sent the PING of the keepalive
+    pinged: bool,
+    broken: bool,
+}
+
+/// Makes the stream of the connection again
+type Redial = Box<dyn Fn() -> io::Result<Link> + Send + Sync>;
 
 impl Connection {
     pub fn new(addr: &str) -> Self {
-        Connection(TcpStream::connect(addr).unwrap())
+        Connection::dial(addr).unwrap()
+    }
+
+    /// Like new, returning the error instead of panicking
+    pub fn dial(addr: &str) -> Result<Self> {
+        let stream = TcpStream::connect(addr).map_err(|e| format!("{}", e))?;
+        let addr = addr.to_string();
+        Ok(Connection::from_transport(stream)
+            .map_err(|e| format!("{}", e))?
+            .redial(move || TcpStream::connect(&addr)))
+    }
+
+    /// Connect to service_unix
+    #[cfg(unix)]
+    pub fn connect_unix(path: &str) -> Self {
+        let path = path.to_string();
+        Connection::from_transport(UnixStream::connect(&path).unwrap())
+            .unwrap()
+            .redial(move || UnixStream::connect(&path))
+    }
+
+    /// Call the functions of srv_fun in this process,
+    /// over an in-memory stream served by another thread
+    pub fn loopback(srv_fun: Fun) -> Self {
+        let (client, server) = pipe();
+        thread::spawn(move || serve_stream(&srv_fun, server));
+        Connection::from_transport(client).unwrap()
+    }
+
+    /// Spawn the command and call the functions it serves
+    /// with serve_stdio, dropping the connection waits for it to exit
+    pub fn spawn(cmd: &mut Command) -> Result<Self> {
+        match Process::spawn(cmd) {
+            Ok(p) => Ok(Connection::from_stream(p)),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+
+    /// Call the functions over a stream of your own,
+    /// e.g. one accepted by a Listener of the service
+    pub fn from_stream<S: Read + Write + Send + 'static>(stream: S) -> Self {
+        Connection {
+            stream: Box::new(stream),
+            callbacks: None,
+            events: VecDeque::new(),
+            timeout: None,
+            redial: None,
+            retry: None,
+            #[cfg(feature = "psk")]
+            psk: None,
+            breaker: None,
+            link: None,
+            keepalive: None,
+            pinged: false,
+            broken: false,
+        }
+    }
+
+    /// Like from_stream, a connection that can be kept alive
+    pub(crate) fn from_transport<S: Transport>(stream: S) -> io::Result<Self> {
+        let (link, _) = transport::link(stream)?;
+        let mut c = Connection::from_stream(link.clone());
+        c.link = Some(link);
+        Ok(c)
+    }
+
+    /// How to make the stream again when it is lost
+    pub(crate) fn redial<S, F>(mut self, f: F) -> Self
+    where
+        S: Transport,
+        F: Fn() -> io::Result<S> + Send + Sync + 'static,
+    {
+        self.redial = Some(Box::new(move || Ok(transport::link(f()?)?.0)));
+        self
+    }
+
+    /// When the connection is lost during invoke, connect again
+    /// and retry the call. A call that may have run already is only
+    /// run again by the server if the function is idempotent.
+    /// Connections made from a stream are not retried,
+    /// subscriptions are lost when connecting again
+    pub fn retry(&mut self, policy: Retry) {
+        self.retry = Some(policy);
+    }
+
+    /// Count the invoke calls with the breaker, which fails them
+    /// without making them while it is open
+    pub fn breaker(&mut self, breaker: Breaker) {
+        self.breaker = Some(breaker);
+    }
+
+    /// Send heartbeats to the server at this interval while the connection
+    /// is idle. The next call asks the server to send them back, whatever
+    /// its own keepalive. From then on the connection is closed when nothing
+    /// is heard from the server for three intervals, even between calls,
+    /// and the next call fails.
+    /// Intervals under 10ms are taken as 10ms.
+    /// Fails unless the connection was made to an address or by loopback
+    pub fn keepalive(&mut self, interval: Duration) -> Result<()> {
+        let interval = interval.max(keepalive::MIN_INTERVAL);
+        let link = match &self.link {
+            Some(link) => link.clone(),
+            None => {
+                return Err(String::from(
+                    "keepalive needs a connection made to an address or by loopback",
+                ))
+            }
+        };
+        if self.keepalive.replace(interval).is_none() {
+            self.stream = Box::new(Inbox::new(link.clone()));
+            keepalive::keepalive(link, interval, Side::Client);
+            self.pinged = false;
+        }
+        Ok(())
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        let link = match &self.redial {
+            Some(redial) => redial().map_err(|e| format!("{}", e))?,
+            None => return Err(String::from("the connection cannot be made again")),
+        };
+        if let Some(old) = self.link.replace(link.clone()) {
+            old.close();
+        }
+        match self.keepalive {
+            Some(interval) => {
+                self.stream = Box::new(Inbox::new(link.clone()));
+                keepalive::keepalive(link, interval, Side::Client);
+                self.pinged = false;
+            }
+            None => self.stream = Box::new(link),
+        }
+        self.events.clear();
+        self.broken = false;
+        #[cfg(feature = "psk")]
+        if let Some((id, key)) = self.psk.clone() {
+            return self.authenticate_as(&id, &key);
+        }
+        Ok(())
+    }
+
+    /// Prove to a server set up with Fun::psk that we know the key
+    /// and check it knows it too, before making any call
+    #[cfg(feature = "psk")]
+    pub fn authenticate(&mut self, key: &[u8]) -> Result<()> {
+        self.authenticate_as("", key)
+    }
+
+    /// Like authenticate, with the key added by Fun::psk_id
+    #[cfg(feature = "psk")]
+    pub fn authenticate_as(&mut self, id: &str, key: &[u8]) -> Result<()> {
+        auth::connect(&mut *self.stream, id, key)?;
+        self.psk = Some((id.to_string(), key.to_vec()));
+        Ok(())
+    }
+
+    /// The calls made after this tell the server how long they are waited for,
+    /// functions see it as the deadline of their Context.
+    /// The connection still waits for the reply after that
+    pub fn deadline(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Functions the server can call back with lrpc::callback
+    /// while it is running a function called on this connection
+    pub fn callbacks(&mut self, fun: Fun) {
+        self.callbacks = Some(fun);
     }
 
     /// Use tcp in the standard library to send data.
@@ -57,26 +255,344 @@ impl Connection {
     /// If the return value of the calling function is of type Result,
     /// it will be reassembled.
     pub fn invoke<T: Store>(&mut self, fun: ByteQue) -> Result<T> {
-        if let Err(e) = self.0.write_all(&send_data(fun)) {
-            return Err(format!("{}", e));
+        let breaker = self.allow()?;
+        let r = self.invoke_retry(fun);
+        if let Some(b) = breaker {
+            b.record_call(self.broken, r.is_err());
+        }
+        r
+    }
+
+    /// Let the call through the breaker if there is one,
+    /// which is then told how the call went
+    fn allow(&self) -> Result<Option<Breaker>> {
+        match &self.breaker {
+            Some(b) => b.allow().map(|_| Some(b.clone())),
+            None => Ok(None),
+        }
+    }
+
+    /// Invoke with the retry policy if there is one
+    fn invoke_retry<T: Store>(&mut self, fun: ByteQue) -> Result<T> {
+        let retry = match self.retry {
+            Some(r) if self.redial.is_some() => r,
+            _ => return Store::restore(&mut self.request(fun)?),
+        };
+        let key = retry::key();
+        let mut attempt = 0u32;
+        loop {
+            let mut q = ByteQue::new();
+            key.store(&mut q);
+            attempt.store(&mut q);
+            q.push_slice(fun.as_slice());
+            let r = match attempt {
+                0 => self.request(frame::wrap(frame::RETRY, q)),
+                _ => self
+                    .reconnect()
+                    .and_then(|_| self.request(frame::wrap(frame::RETRY, q))),
+            };
+            match r {
+                Ok(mut r) => return Store::restore(&mut r),
+                Err(e) if attempt + 1 >= retry.attempts => return Err(e),
+                Err(_) => {
+                    attempt += 1;
+                    retry.wait(attempt);
+                }
+            }
+        }
+    }
+
+    fn request(&mut self, q: ByteQue) -> Result<ByteQue> {
+        self.send_call(q)?;
+        self.recv()
+    }
+
+    /// Send a call, telling the server its deadline if there is one
+    fn send_call(&mut self, q: ByteQue) -> Result<()> {
+        if let (Some(interval), false) = (self.keepalive, self.pinged) {
+            let mut p = ByteQue::new();
+            (interval.as_millis() as u64).store(&mut p);
+            self.send(frame::wrap(frame::PING, p))?;
+            self.pinged = true;
+        }
+        let timeout = match self.timeout {
+            Some(t) => t,
+            None => return self.send(q),
+        };
+        let mut d = ByteQue::new();
+        (timeout.as_millis() as u64).store(&mut d);
+        d.push_slice(q.as_slice());
+        self.send(frame::wrap(frame::DEADLINE, d))
+    }
+
+    fn send(&mut self, q: ByteQue) -> Result<()> {
+        match self.stream.write_all(&send_data(q)) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                self.broken = true;
+                Err(format!("{}", e))
+            }
         }
-        let mut recv = RecvBuf::new();
-        let mut buf = [0u8; 1024];
+    }
+
+    fn read(&mut self) -> Result<ByteQue> {
+        let e = match keepalive::recv(&mut self.stream) {
+            Ok(q) => return Ok(q),
+            Err(e) => e,
+        };
+        self.broken = true;
+        match e.kind() {
+            ErrorKind::UnexpectedEof => Err(String::from("the server is disconnected")),
+            _ => Err(format!("{}", e)),
+        }
+    }
+
+    /// Whether sending or receiving failed, the connection is then unusable
+    /// unless it is made again by a retry
+    pub fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    fn answer(&mut self, mut q: ByteQue) -> Result<()> {
+        frame::unwrap(&mut q);
+        let r = match &self.callbacks {
+            Some(f) => f.invoke(&mut q),
+            None => {
+                let mut r = ByteQue::new();
+                Result::<()>::Err(String::from("no callbacks are registered")).store(&mut r);
+                r
+            }
+        };
+        self.send(r)
+    }
+
+    /// Read the next reply, answering the callbacks
+    /// and keeping the events that come before it
+    fn recv(&mut self) -> Result<ByteQue> {
         loop {
-            match recv.size() {
-                Some(s) if s == recv.len() => break,
-                _ => match self.0.read(&mut buf) {
-                    Ok(l) => {
-                        if l > 0 {
-                            recv.append(&buf[..l]);
-                        } else {
-                            return Err(String::from("the server is disconnected"));
-                        }
-                    }
-                    Err(e) => return Err(format!("{}", e)),
+            let q = self.read()?;
+            match frame::push_kind(&q) {
+                Some(frame::CALLBACK) => self.answer(q)?,
+                Some(frame::PUBLISH) => self.events.push_back(q),
+                _ => return Ok(q),
+            }
+        }
+    }
+
+    /// Receive the events the server publishes to the topic
+    pub fn subscribe(&mut self, topic: &str) -> Result<()> {
+        let mut q = ByteQue::new();
+        topic.to_string().store(&mut q);
+        Store::restore(&mut self.request(frame::wrap(frame::SUBSCRIBE, q))?)
+    }
+
+    pub fn unsubscribe(&mut self, topic: &str) -> Result<()> {
+        let mut q = ByteQue::new();
+        topic.to_string().store(&mut q);
+        Store::restore(&mut self.request(frame::wrap(frame::UNSUBSCRIBE, q))?)
+    }
+
+    /// Wait for the next event of the subscribed topics,
+    /// returns its topic and the event
+    pub fn event<T: Store>(&mut self) -> Result<(String, T)> {
+        let mut q = match self.events.pop_front() {
+            Some(q) => q,
+            None => loop {
+                let q = self.read()?;
+                match frame::push_kind(&q) {
+                    Some(frame::CALLBACK) => self.answer(q)?,
+                    Some(frame::PUBLISH) => break q,
+                    _ => return Err(String::from("unexpected reply while waiting for an event")),
+                }
+            },
+        };
+        frame::unwrap(&mut q);
+        Ok(Store::restore(&mut q))
+    }
+
+    /// Collect several calls and send them in one frame
+    pub fn batch(&mut self) -> Batch<'_> {
+        Batch {
+            conn: self,
+            calls: Vec::new(),
+            flags: 0,
+        }
+    }
+
+    /// Send the call without waiting,
+    /// the server executes it and does not reply
+    pub fn notify(&mut self, fun: ByteQue) -> Result<()> {
+        let breaker = self.allow()?;
+        let r = self.send_call(frame::wrap(frame::NOTIFY, fun));
+        if let Some(b) = breaker {
+            b.record_call(self.broken, false);
+        }
+        r
+    }
+
+    /// Sends the call, telling the breaker if it could not be sent
+    fn send_counted(&mut self, q: ByteQue, breaker: &Option<Breaker>) -> Result<()> {
+        let r = self.send_call(q);
+        if let (Err(_), Some(b)) = (&r, breaker) {
+            b.record_call(true, true);
+        }
+        r
+    }
+
+    /// Call a function registered with Fun::regist_upload,
+    /// send the items and finish to get its result
+    pub fn upload(&mut self, fun: ByteQue) -> Result<Upload<'_>> {
+        let breaker = self.allow()?;
+        self.send_counted(frame::wrap(frame::UPLOAD, fun), &breaker)?;
+        let chan = Chan::new(&mut *self.stream, Some(&mut self.events));
+        Ok(Upload(chan.breaker(breaker)))
+    }
+
+    /// Call a function registered with Fun::regist_duplex
+    pub fn duplex(&mut self, fun: ByteQue) -> Result<Duplex<'_>> {
+        let breaker = self.allow()?;
+        self.send_counted(frame::wrap(frame::DUPLEX, fun), &breaker)?;
+        let chan = Chan::new(&mut *self.stream, Some(&mut self.events));
+        Ok(Duplex(chan.breaker(breaker)))
+    }
+
+    /// Call a function registered with Fun::regist_stream,
+    /// its items are read as the iterator advances
+    pub fn stream<T: Store>(&mut self, fun: ByteQue) -> Result<Stream<'_, T>> {
+        let breaker = self.allow()?;
+        self.send_counted(frame::wrap(frame::STREAM, fun), &breaker)?;
+        Ok(Stream {
+            conn: self,
+            done: false,
+            breaker,
+            item: PhantomData,
+        })
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // stops the keepalive and reading threads
+        if let Some(link) = &self.link {
+            link.close();
+        }
+    }
+}
+
+/// Calls sent together by Connection::batch,
+/// by default the server runs them concurrently
+pub struct Batch<'a> {
+    conn: &'a mut Connection,
+    calls: Vec<ByteQue>,
+    flags: u8,
+}
+
+impl Batch<'_> {
+    pub fn call(mut self, fun: ByteQue) -> Self {
+        self.calls.push(fun);
+        self
+    }
+
+    /// Run the calls one after another in the order they were added
+    pub fn in_order(mut self) -> Self {
+        self.flags |= frame::IN_ORDER;
+        self
+    }
+
+    /// Run in order and skip the calls after the first error,
+    /// the skipped calls return an error
+    pub fn stop_on_error(mut self) -> Self {
+        self.flags |= frame::IN_ORDER | frame::STOP_ON_ERROR;
+        self
+    }
+
+    /// One result for each call, in the order they were added
+    pub fn send<T: Store>(self) -> Result<Vec<Result<T>>> {
+        Ok(self.send_raw()?.iter_mut().map(Store::restore).collect())
+    }
+
+    /// Like send, for calls returning different types.
+    /// Each reply is restored as Result<T> with the type of its call.
+    /// Fails unless there is a reply for each call
+    pub fn send_raw(self) -> Result<Vec<ByteQue>> {
+        let mut q = ByteQue::new();
+        self.flags.store(&mut q);
+        frame::store_list(&self.calls, &mut q);
+        let calls = self.calls.len();
+        let breaker = self.conn.allow()?;
+        let r =
+            self.conn
+                .request(frame::wrap(frame::BATCH, q))
+                .and_then(|mut r| match bool::restore(&mut r) {
+                    true => Err(String::restore(&mut r)),
+                    false => match frame::restore_list(&mut r) {
+                        replies if replies.len() == calls => Ok(replies),
+                        replies => Err(format!("{} replies to {} calls", replies.len(), calls)),
+                    },
+                });
+        if let Some(b) = breaker {
+            let error = match &r {
+                // each reply is a Result, starting with true for an error
+                Ok(replies) => replies.iter().any(|q| q.as_slice().first() != Some(&0)),
+                Err(_) => true,
+            };
+            b.record_call(self.conn.broken, error);
+        }
+        r
+    }
+}
+
+/// Items of a stream function returned by Connection::stream.
+/// An error ends the stream, dropping it early
+/// reads and discards the remaining items.
+pub struct Stream<'a, T> {
+    conn: &'a mut Connection,
+    done: bool,
+    /// Told how the stream ended
+    breaker: Option<Breaker>,
+    item: PhantomData<T>,
+}
+
+impl<T> Stream<'_, T> {
+    fn end(&mut self, error: bool) {
+        self.done = true;
+        if let Some(b) = self.breaker.take() {
+            b.record_call(self.conn.broken, error);
+        }
+    }
+}
+
+impl<T: Store> Iterator for Stream<'_, T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let r = match self.conn.recv() {
+            Ok(mut q) => Option::<Result<T>>::restore(&mut q),
+            Err(e) => Some(Err(e)),
+        };
+        match r {
+            Some(Ok(_)) => {}
+            Some(Err(_)) => self.end(true),
+            None => self.end(false),
+        }
+        r
+    }
+}
+
+impl<T> Drop for Stream<'_, T> {
+    fn drop(&mut self) {
+        while !self.done {
+            match self.conn.recv() {
+                Ok(q) => match q.as_slice() {
+                    [1, 0, ..] => {}
+                    [1, ..] => self.end(true),
+                    _ => self.end(false),
                 },
+                Err(_) => self.end(true),
             }
         }
-        Store::restore(&mut recv.into())
     }
 }
@@ -1,6 +1,28 @@
 use crate::*;
 
+/// Bind a free local port, the address to connect to
+fn bind() -> (std::net::TcpListener, String) {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    (listener, addr)
+}
+
+/// Serve fun on a free local port, the calls made
+/// to the returned address wait until it is accepted
+fn serve_local(fun: Fun) -> String {
+    let (listener, addr) = bind();
+    std::thread::spawn(move || service_on(fun, listener));
+    addr
+}
+
 #[test]
+#[allow(
+    clippy::toplevel_ref_arg,
+    clippy::approx_constant,
+    clippy::excessive_precision,
+    clippy::let_unit_value,
+    clippy::unit_cmp
+)]
 fn test_val() {
     let ref mut q = ByteQue::new();
 
@@ -136,6 +158,185 @@ fn test_fun() {
     assert_eq!(Result::<i32>::restore(&mut fun.invoke(&mut r)), Ok(2));
 }
 
+#[test]
+fn test_fun_id() {
+    #[fmt_function]
+    fn add(x: i32, y: i32) -> i32 {
+        x + y
+    }
+
+    #[fmt_function(name = "geometry.square")]
+    fn square(x: i32) -> i32 {
+        x * x
+    }
+
+    #[fmt_function]
+    fn which(id: FunId) -> FunId {
+        id
+    }
+
+    let mut fun = Fun::new();
+    fun.service("geometry").regist("add", add);
+    fun.regist(square::NAME, square);
+    fun.regist("which", which);
+    const SQUARE: FunId = FunId::new(square::NAME);
+    let mut r = fun_id!(SQUARE, 5);
+    assert_eq!(Result::<i32>::restore(&mut fun.invoke(&mut r)), Ok(25));
+    let mut r = fun_id!("geometry.add", 2, 3);
+    assert_eq!(Result::<i32>::restore(&mut fun.invoke(&mut r)), Ok(5));
+    let mut r = fun_id!("missing");
+    assert!(Result::<i32>::restore(&mut fun.invoke(&mut r)).is_err());
+
+    // an id is a plain value as an argument
+    let mut r = fun!("which", SQUARE);
+    assert_eq!(
+        Result::<FunId>::restore(&mut fun.invoke(&mut r)),
+        Ok(SQUARE)
+    );
+    let q = &mut ByteQue::new();
+    SQUARE.store(q);
+    assert_eq!(q.len(), 4);
+    assert_eq!(FunId::restore(q), SQUARE);
+}
+
+#[test]
+fn test_panic() {
+    use std::sync::Mutex;
+
+    #[fmt_function]
+    fn divide(x: i32, y: i32) -> i32 {
+        x / y
+    }
+
+    static PANICS: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+    let mut fun = Fun::new();
+    fun.regist("divide", divide);
+    fun.on_panic(|call, msg| {
+        PANICS
+            .lock()
+            .unwrap()
+            .push((call.name.to_string(), msg.to_string()))
+    });
+    let mut con = Connection::loopback(fun);
+    let rst: Result<i32> = con.invoke(fun!("divide", 1, 0));
+    assert_eq!(
+        rst,
+        Err("divide function panicked: attempt to divide by zero".to_string())
+    );
+    assert_eq!(
+        PANICS.lock().unwrap().as_slice(),
+        [(
+            "divide".to_string(),
+            "attempt to divide by zero".to_string()
+        )]
+    );
+    // the connection is still served
+    let rst: Result<i32> = con.invoke(fun!("divide", 6, 3));
+    assert_eq!(rst, Ok(2));
+
+    // a hook that panics too is ignored
+    let mut fun = Fun::new();
+    fun.regist("divide", divide);
+    fun.on_panic(|_, _| panic!("the hook panics too"));
+    let mut r = fun!("divide", 1, 0);
+    assert_eq!(
+        Result::<i32>::restore(&mut fun.invoke(&mut r)),
+        Err("divide function panicked: attempt to divide by zero".to_string())
+    );
+}
+
+#[test]
+fn test_service() {
+    #[fmt_function]
+    fn add(x: i32, y: i32) -> i32 {
+        x + y
+    }
+
+    #[fmt_function(name = "geometry.square")]
+    fn square(x: i32) -> i32 {
+        x * x
+    }
+
+    let mut fun = Fun::new();
+    fun.regist("add", add);
+    let mut geometry = Fun::new();
+    geometry.service("geometry").regist("add", add);
+    geometry
+        .service("geometry")
+        .service("plane")
+        .regist("add", add);
+    geometry.regist(square::NAME, square);
+    assert_eq!(square::NAME, "geometry.square");
+    assert!(fun.merge(geometry).is_ok());
+    let mut r = fun!("geometry.add", 2, 3);
+    assert_eq!(Result::<i32>::restore(&mut fun.invoke(&mut r)), Ok(5));
+    let mut r = fun!("geometry.plane.add", 3, 4);
+    assert_eq!(Result::<i32>::restore(&mut fun.invoke(&mut r)), Ok(7));
+    let mut r = fun!(square::NAME, 4);
+    assert_eq!(Result::<i32>::restore(&mut fun.invoke(&mut r)), Ok(16));
+
+    // nothing is merged if a name is taken
+    let mut other = Fun::new();
+    other.regist("add", add);
+    other.service("geometry").regist("square", square);
+    other.regist("other", add);
+    assert_eq!(
+        fun.merge(other),
+        Err("add, geometry.square functions are already registered".to_string())
+    );
+    let mut r = fun!("other", 1, 1);
+    assert!(Result::<i32>::restore(&mut fun.invoke(&mut r)).is_err());
+    let mut fun = std::panic::AssertUnwindSafe(fun);
+    assert!(std::panic::catch_unwind(move || fun.regist("add", add)).is_err());
+}
+
+#[test]
+fn test_interceptor() {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    };
+
+    #[fmt_function]
+    fn half(x: i32) -> i32 {
+        x / 2
+    }
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let calls = Arc::new(AtomicUsize::new(0));
+    let mut fun = Fun::new();
+    fun.regist("half", half);
+    let l = log.clone();
+    fun.around(move |call, q, next| {
+        l.lock().unwrap().push(format!("enter {}", call.name));
+        let r = next(q);
+        l.lock().unwrap().push(format!("leave {}", call.name));
+        r
+    });
+    fun.before(|_, args| {
+        let x = i32::restore(&mut ByteQue::from(args.to_vec()));
+        if x % 2 != 0 {
+            return Err(format!("{} is odd", x));
+        }
+        Ok(())
+    });
+    let c = calls.clone();
+    fun.after(move |call, ret| {
+        assert_eq!(call.peer, "127.0.0.1:1");
+        assert_eq!(ret[0], 0);
+        c.fetch_add(1, Ordering::SeqCst);
+    });
+
+    let r: Result<i32> = Store::restore(&mut fun.invoke_from("127.0.0.1:1", &mut fun!("half", 8)));
+    assert_eq!(r, Ok(4));
+    let r: Result<i32> = Store::restore(&mut fun.invoke(&mut fun_id!("half", 7)));
+    assert_eq!(r, Err("7 is odd".to_string()));
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    assert_eq!(
+        *log.lock().unwrap(),
+        vec!["enter half", "leave half", "enter half", "leave half"]
+    );
+}
+
 #[test]
 fn test_derive_macros() {
     let mut q = ByteQue::new();
@@ -301,6 +502,14 @@ fn test_buf() {
     r.append(v.as_slice());
     let mut q = ByteQue::from(r);
     assert_eq!(String::restore(&mut q), s);
+
+    let mut v = send_data(fun!("first"));
+    v.append(&mut send_data(fun!("second")));
+    v.push(3);
+    let mut r = v.as_slice();
+    assert_eq!(String::restore(&mut recv_data(&mut r).unwrap()), "first");
+    assert_eq!(String::restore(&mut recv_data(&mut r).unwrap()), "second");
+    assert!(recv_data(&mut r).is_err());
 }
 
 #[test]
@@ -329,6 +538,7 @@ fn test_tcp() {
     fn bublle2(arr: Vec<i32>) -> Vec<i32> {
         bubble_sort(arr)
     }
+    #[allow(clippy::manual_swap)]
     fn bubble_sort(mut arr: Vec<i32>) -> Vec<i32> {
         for i in 0..arr.len() - 1 {
             for j in 0..arr.len() - 1 - i {
@@ -346,12 +556,9 @@ fn test_tcp() {
     fun.regist("value_in_cents", value_in_cents);
     fun.regist("bublle1", bublle1);
 
-    std::thread::spawn(move || {
-        service(fun, "0.0.0.0:9009");
-    });
-    std::thread::sleep(std::time::Duration::from_millis(10));
+    let addr = serve_local(fun);
 
-    let mut con = Connection::new("127.0.0.1:9009");
+    let mut con = Connection::new(&addr);
 
     let rst: Result<u8> = con.invoke(fun!("value_in_cents", Coin::Quarter));
     assert_eq!(rst, Ok(25));
@@ -369,3 +576,1688 @@ fn test_tcp() {
     dbg!(t3 - t2);
     assert_eq!(r1.unwrap(), r2);
 }
+
+#[test]
+fn test_upload() {
+    fn sum(q: &mut ByteQue, rx: &mut Receiver) -> ByteQue {
+        let limit = u32::restore(q);
+        let mut n = 0;
+        let mut r = ByteQue::new();
+        while let Some(x) = rx.recv::<u32>() {
+            n += x.unwrap();
+            if n > limit {
+                Result::<u32>::Err("limit exceeded".to_string()).store(&mut r);
+                return r;
+            }
+        }
+        Result::<u32>::Ok(n).store(&mut r);
+        r
+    }
+    let mut fun = Fun::new();
+    fun.regist_upload("sum", sum);
+    let mut con = Connection::loopback(fun);
+
+    let mut up = con.upload(fun!("sum", 100000u32)).unwrap();
+    for i in 0..100u32 {
+        up.send(&i).unwrap();
+    }
+    assert_eq!(up.finish::<u32>(), Ok(4950));
+    let mut up = con.upload(fun!("sum", 10u32)).unwrap();
+    let mut sent = 0;
+    while up.send(&1u32).is_ok() {
+        sent += 1;
+    }
+    assert!(sent > 10);
+    assert_eq!(up.finish::<u32>(), Err("limit exceeded".to_string()));
+}
+
+#[test]
+fn test_duplex() {
+    fn sum(q: &mut ByteQue, rx: &mut Receiver) -> ByteQue {
+        let limit = u32::restore(q);
+        let mut n = 0;
+        let mut r = ByteQue::new();
+        while let Some(x) = rx.recv::<u32>() {
+            n += x.unwrap();
+            if n > limit {
+                Result::<u32>::Err("limit exceeded".to_string()).store(&mut r);
+                return r;
+            }
+        }
+        Result::<u32>::Ok(n).store(&mut r);
+        r
+    }
+    fn double(_: &mut ByteQue, d: &mut Duplex) -> Result<()> {
+        while let Some(x) = d.recv::<i64>() {
+            d.send(&(x? * 2))?;
+        }
+        Ok(())
+    }
+
+    fn flood(_: &mut ByteQue, d: &mut Duplex) -> Result<()> {
+        for i in 0..48i64 {
+            d.send(&i)?;
+        }
+        let mut n = 0i64;
+        while let Some(x) = d.recv::<i64>() {
+            assert_eq!(x?, n);
+            n += 1;
+        }
+        if n == 48 {
+            Ok(())
+        } else {
+            Err(format!("{} items", n))
+        }
+    }
+
+    let mut fun = Fun::new();
+    fun.regist_upload("sum", sum);
+    fun.regist_duplex("double", double);
+    fun.regist_duplex("flood", flood);
+    let mut con = Connection::loopback(fun);
+
+    let mut d = con.duplex(fun!("double")).unwrap();
+    for i in 0..100i64 {
+        d.send(&i).unwrap();
+        assert_eq!(d.recv::<i64>(), Some(Ok(i * 2)));
+    }
+    d.send(&7i64).unwrap();
+    assert_eq!(d.finish(), Ok(()));
+    drop(con.duplex(fun!("double")).unwrap());
+    // both sides send before receiving, within the two windows
+    let mut d = con.duplex(fun!("flood")).unwrap();
+    for i in 0..48i64 {
+        d.send(&i).unwrap();
+    }
+    for i in 0..48i64 {
+        assert_eq!(d.recv::<i64>(), Some(Ok(i)));
+    }
+    assert_eq!(d.finish(), Ok(()));
+    let mut d = con.duplex(fun!("sum", 1u32)).unwrap();
+    assert_eq!(d.recv::<i64>(), None);
+    assert_eq!(
+        d.finish(),
+        Err("sum function is an upload function".to_string())
+    );
+}
+
+#[test]
+fn test_duplex_credit() {
+    use crate::chan::Chan;
+    use std::io::Write;
+
+    let (mut a, mut b) = crate::mem::pipe();
+    // a peer that sends items without waiting for credit
+    for i in 0..100i64 {
+        let mut q = ByteQue::new();
+        0u8.store(&mut q);
+        i.store(&mut q);
+        b.write_all(&send_data(q)).unwrap();
+    }
+    let mut chan = Chan::new(&mut a, None);
+    let mut sent = 0i64;
+    let rst = loop {
+        let mut q = ByteQue::new();
+        sent.store(&mut q);
+        if let Err(e) = chan.send(&q) {
+            break e;
+        }
+        sent += 1;
+    };
+    // a blocked sender grants one more window, then refuses the rest
+    assert_eq!(sent, 32);
+    assert_eq!(rst, "the other side sent an item without credit");
+}
+
+#[test]
+fn test_callback() {
+    use std::sync::atomic::{AtomicI32, Ordering};
+
+    #[fmt_function]
+    fn steps(n: i32) -> Result<i32> {
+        let mut total = 0;
+        for i in 1..=n {
+            total = callback::<i32>(fun!("progress", i))?;
+        }
+        Ok(total)
+    }
+
+    static PROGRESS: AtomicI32 = AtomicI32::new(0);
+    #[fmt_function]
+    fn progress(i: i32) -> i32 {
+        PROGRESS.fetch_add(i, Ordering::SeqCst) + i
+    }
+
+    static NOTIFIED: std::sync::Mutex<Option<Result<i32>>> = std::sync::Mutex::new(None);
+    #[fmt_function]
+    fn progress_notified(i: i32) {
+        *NOTIFIED.lock().unwrap() = Some(callback::<i32>(fun!("progress", i)));
+    }
+
+    let mut fun = Fun::new();
+    fun.regist("steps", steps);
+    fun.regist("progress_notified", progress_notified);
+    let mut con = Connection::loopback(fun);
+
+    let rst: Result<i32> = con.invoke(fun!("steps", 3));
+    assert_eq!(rst, Err("no callbacks are registered".to_string()));
+    let mut callbacks = Fun::new();
+    callbacks.regist("progress", progress);
+    con.callbacks(callbacks);
+    let rst: Result<i32> = con.invoke(fun!("steps", 4));
+    assert_eq!(rst, Ok(10));
+    assert_eq!(
+        callback::<i32>(fun!("progress", 1)),
+        Err("there is no caller to call back".to_string())
+    );
+    // the calls of a concurrent batch can call back too
+    let rst: Vec<Result<i32>> = con
+        .batch()
+        .call(fun!("steps", 2))
+        .call(fun!("steps", 3))
+        .send()
+        .unwrap();
+    assert!(rst.iter().all(|r| r.is_ok()));
+    assert_eq!(PROGRESS.load(Ordering::SeqCst), 19);
+
+    // nobody waits on a notification, it cannot call back
+    assert_eq!(con.notify(fun!("progress_notified", 2)), Ok(()));
+    let rst: Result<i32> = con.invoke(fun!("steps", 1));
+    assert_eq!(rst, Ok(20));
+    assert_eq!(PROGRESS.load(Ordering::SeqCst), 20);
+    assert_eq!(
+        *NOTIFIED.lock().unwrap(),
+        Some(Err("there is no caller to call back".to_string()))
+    );
+}
+
+#[test]
+fn test_notify() {
+    use std::sync::atomic::{AtomicI32, Ordering};
+
+    static NOTIFIED: AtomicI32 = AtomicI32::new(0);
+
+    #[fmt_function]
+    fn record(x: i32) {
+        NOTIFIED.fetch_add(x, Ordering::SeqCst);
+    }
+
+    let mut fun = Fun::new();
+    fun.regist("record", record);
+    let mut con = Connection::loopback(fun);
+    assert_eq!(con.notify(fun!("record", 3)), Ok(()));
+    assert_eq!(con.notify(fun_id!("record", 4)), Ok(()));
+    // run in order, before the call made after them
+    let rst: Result<()> = con.invoke(fun!("record", 5));
+    assert_eq!(rst, Ok(()));
+    assert_eq!(NOTIFIED.load(Ordering::SeqCst), 12);
+}
+
+#[test]
+fn test_batch() {
+    use std::io::Write;
+
+    #[fmt_function]
+    fn divide(x: i32, y: i32) -> i32 {
+        x / y
+    }
+
+    #[fmt_function]
+    fn name() -> String {
+        String::from("lrpc")
+    }
+
+    let mut fun = Fun::new();
+    fun.regist("divide", divide);
+    fun.regist("name", name);
+    let mut con = Connection::loopback(fun);
+    let rst: Result<Vec<Result<i32>>> = con
+        .batch()
+        .call(fun!("divide", 8, 2))
+        .call(fun!("divide", 9, 3))
+        .call(fun_id!("divide", 1, 0))
+        .send();
+    let rst = rst.unwrap();
+    assert_eq!(rst[..2], [Ok(4), Ok(3)]);
+    assert!(rst[2].is_err());
+    let rst: Result<Vec<Result<i32>>> = con
+        .batch()
+        .call(fun!("divide", 8, 0))
+        .call(fun!("divide", 9, 3))
+        .stop_on_error()
+        .send();
+    assert_eq!(
+        rst.unwrap()[1],
+        Err("not executed after an earlier error".to_string())
+    );
+    let mut rst = con
+        .batch()
+        .call(fun!("divide", 8, 2))
+        .call(fun!("name"))
+        .in_order()
+        .send_raw()
+        .unwrap();
+    assert_eq!(Result::<i32>::restore(&mut rst[0]), Ok(4));
+    assert_eq!(
+        Result::<String>::restore(&mut rst[1]),
+        Ok("lrpc".to_string())
+    );
+    assert_eq!(con.batch().send::<i32>(), Ok(vec![]));
+
+    // a call longer than the frame is not read past its end
+    let mut q = ByteQue::new();
+    1usize.store(&mut q);
+    100usize.store(&mut q);
+    q.push_slice(&[1, 2]);
+    assert!(crate::frame::restore_list(&mut q).is_empty());
+
+    // a server answering with fewer replies than calls
+    let (client, mut server) = crate::mem::pipe();
+    std::thread::spawn(move || {
+        recv_data(&mut server).unwrap();
+        let mut q = ByteQue::new();
+        false.store(&mut q);
+        crate::frame::store_list(&[], &mut q);
+        server.write_all(&send_data(q)).unwrap();
+    });
+    let mut con = Connection::from_transport(client).unwrap();
+    assert_eq!(
+        con.batch()
+            .call(fun!("divide", 8, 2))
+            .call(fun!("divide", 9, 3))
+            .send::<i32>(),
+        Err("0 replies to 2 calls".to_string())
+    );
+}
+
+#[test]
+fn test_stream() {
+    #[fmt_function]
+    fn divide(x: i32, y: i32) -> i32 {
+        x / y
+    }
+
+    fn countdown(q: &mut ByteQue, tx: &mut Sender) -> Result<()> {
+        let mut n = u32::restore(q);
+        while n > 0 {
+            tx.send(&n)?;
+            n -= 1;
+        }
+        if u32::restore(q) != 0 {
+            return Err("liftoff aborted".to_string());
+        }
+        Ok(())
+    }
+
+    let mut fun = Fun::new();
+    fun.regist("divide", divide);
+    fun.regist_stream("countdown", countdown);
+    let mut con = Connection::loopback(fun);
+    let rst: Vec<Result<u32>> = con.stream(fun!("countdown", 3u32, 0u32)).unwrap().collect();
+    assert_eq!(rst, vec![Ok(3), Ok(2), Ok(1)]);
+    let rst: Vec<Result<u32>> = con
+        .stream(fun_id!("countdown", 2u32, 1u32))
+        .unwrap()
+        .collect();
+    assert_eq!(rst, vec![Ok(2), Ok(1), Err("liftoff aborted".to_string())]);
+    // the rest of a stream dropped early is skipped
+    let mut items = con.stream::<u32>(fun!("countdown", 100u32, 0u32)).unwrap();
+    assert_eq!(items.next(), Some(Ok(100)));
+    drop(items);
+    let rst: Vec<Result<u32>> = con.stream(fun!("divide", 1, 1)).unwrap().collect();
+    assert_eq!(
+        rst,
+        vec![Err("divide function is a plain function".to_string())]
+    );
+    let rst: Result<u32> = con.invoke(fun!("countdown", 1u32, 0u32));
+    assert_eq!(
+        rst,
+        Err("countdown function is a stream function".to_string())
+    );
+}
+
+#[test]
+fn test_pubsub() {
+    let hub = Hub::new(16, SlowConsumer::DropOldest);
+    let mut fun = Fun::new();
+    fun.pubsub(hub.clone());
+    let addr = serve_local(fun);
+
+    let mut con1 = Connection::new(&addr);
+    let mut con2 = Connection::new(&addr);
+    assert_eq!(con1.subscribe("prices"), Ok(()));
+    assert_eq!(con1.subscribe("news"), Ok(()));
+    assert_eq!(con2.subscribe("prices"), Ok(()));
+    assert_eq!(hub.subscribers("prices"), 2);
+    assert_eq!(hub.publish("prices", &101.5f64), 2);
+    assert_eq!(hub.publish("news", &"rates up".to_string()), 1);
+    assert_eq!(con1.event(), Ok(("prices".to_string(), 101.5f64)));
+    assert_eq!(
+        con1.event(),
+        Ok(("news".to_string(), "rates up".to_string()))
+    );
+    assert_eq!(con2.event(), Ok(("prices".to_string(), 101.5f64)));
+
+    assert_eq!(con2.unsubscribe("prices"), Ok(()));
+    assert_eq!(hub.subscribers("prices"), 1);
+    drop(con1);
+    // left once the server sees the connection end
+    while hub.subscribers("prices") > 0 {
+        std::thread::yield_now();
+    }
+    assert_eq!(hub.publish("prices", &99f64), 0);
+
+    // the subscriber's writer blocks until released
+    use std::sync::mpsc;
+    struct Gate(mpsc::Sender<Vec<u8>>, mpsc::Receiver<()>);
+    impl std::io::Write for Gate {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.send(buf.to_vec()).unwrap();
+            self.1.recv().unwrap();
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    for policy in [SlowConsumer::DropOldest, SlowConsumer::DropNewest] {
+        let hub = Hub::new(2, policy);
+        let (written, seen) = mpsc::channel();
+        let (release, gate) = mpsc::channel();
+        let link = crate::link::Link::new(std::io::empty(), Gate(written, gate), || ());
+        let s = hub.join(link);
+        hub.subscribe("t", &s);
+        hub.publish("t", &0u8);
+        let mut w = seen.recv().unwrap();
+        for i in 1..5u8 {
+            hub.publish("t", &i);
+        }
+        let mut got = Vec::new();
+        loop {
+            let mut q = recv_data(&mut w.as_slice()).unwrap();
+            q.pop_slice(2);
+            got.push(<(String, u8)>::restore(&mut q).1);
+            release.send(()).unwrap();
+            if got.len() == 3 {
+                break;
+            }
+            w = seen.recv().unwrap();
+        }
+        match policy {
+            SlowConsumer::DropOldest => assert_eq!(got, vec![0, 3, 4]),
+            _ => assert_eq!(got, vec![0, 1, 2]),
+        }
+        hub.leave(&s);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_unix() {
+    use std::os::unix::fs::PermissionsExt;
+
+    #[fmt_function]
+    fn shout(s: String) -> String {
+        s.to_uppercase()
+    }
+    #[fmt_function]
+    fn who(ctx: Context) -> String {
+        ctx.peer
+    }
+    let path = std::env::temp_dir().join(format!("lrpc-{}.sock", std::process::id()));
+    let path = path.to_str().unwrap().to_string();
+    // a socket file left behind by a previous server
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+    let mut fun = Fun::new();
+    fun.regist("shout", shout);
+    fun.regist("who", who);
+    let p = path.clone();
+    std::thread::spawn(move || {
+        service_unix_mode(fun, &p, 0o600);
+    });
+    // the old file is replaced once the server listens
+    while std::os::unix::net::UnixStream::connect(&path).is_err() {
+        std::thread::yield_now();
+    }
+
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    let mut con = Connection::connect_unix(&path);
+    let rst: Result<String> = con.invoke(fun!("shout", "hello".to_string()));
+    assert_eq!(rst, Ok("HELLO".to_string()));
+    // each connection is a peer of its own
+    let first: String = con.invoke(fun!("who")).unwrap();
+    let second: String = Connection::connect_unix(&path).invoke(fun!("who")).unwrap();
+    assert!(first.starts_with("unix:"));
+    assert_ne!(first, second);
+    let p = path.clone();
+    assert!(std::panic::catch_unwind(move || service_unix(Fun::new(), &p)).is_err());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_transport() {
+    use std::{
+        io::{self, Read, Write},
+        net::{TcpListener, TcpStream},
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    static WRITTEN: AtomicUsize = AtomicUsize::new(0);
+
+    // counts the bytes the service writes, before writing them
+    // so that a caller reading the reply sees them counted
+    struct Counted(TcpStream);
+
+    impl Read for Counted {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for Counted {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            WRITTEN.fetch_add(buf.len(), Ordering::SeqCst);
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    impl Transport for Counted {
+        fn try_clone(&self) -> io::Result<Self> {
+            Ok(Counted(self.0.try_clone()?))
+        }
+
+        fn close(&self) {
+            Transport::close(&self.0)
+        }
+
+        fn peer(&self) -> String {
+            String::from("counted")
+        }
+    }
+
+    // accepts a single connection
+    struct Once(TcpListener);
+
+    impl Listener for Once {
+        type Stream = Counted;
+
+        fn accept(&self) -> Option<Counted> {
+            if WRITTEN.load(Ordering::SeqCst) > 0 {
+                return None;
+            }
+            Listener::accept(&self.0).map(Counted)
+        }
+    }
+
+    #[fmt_function]
+    fn ping() -> String {
+        String::from("pong")
+    }
+
+    let mut fun = Fun::new();
+    fun.regist("ping", ping);
+    fun.before(|call, _| match call.peer {
+        "counted" => Ok(()),
+        p => Err(format!("unexpected peer {}", p)),
+    });
+    let (listener, addr) = bind();
+    let listener = Once(listener);
+    let server = std::thread::spawn(move || service_on(fun, listener));
+
+    let mut con = Connection::from_stream(TcpStream::connect(&addr).unwrap());
+    let rst: Result<String> = con.invoke(fun!("ping"));
+    assert_eq!(rst, Ok(String::from("pong")));
+    assert!(WRITTEN.load(Ordering::SeqCst) > 0);
+    drop(Connection::new(&addr));
+    server.join().unwrap();
+}
+
+#[test]
+fn test_loopback() {
+    use std::io::{Read, Write};
+
+    #[fmt_function]
+    fn concat(a: String, b: String) -> String {
+        a + &b
+    }
+
+    fn count(q: &mut ByteQue, tx: &mut Sender) -> Result<()> {
+        for i in 0..u32::restore(q) {
+            tx.send(&i)?;
+        }
+        Ok(())
+    }
+
+    let mut fun = Fun::new();
+    fun.regist("concat", concat);
+    fun.regist_stream("count", count);
+    let mut con = Connection::loopback(fun);
+    let rst: Result<String> = con.invoke(fun!("concat", "lo".to_string(), "op".to_string()));
+    assert_eq!(rst, Ok("loop".to_string()));
+    let rst: Vec<Result<u32>> = con.stream(fun!("count", 100u32)).unwrap().collect();
+    assert_eq!(rst, (0..100).map(Ok).collect::<Vec<_>>());
+
+    let (mut a, mut b) = pipe();
+    a.write_all(b"abc").unwrap();
+    let mut buf = [0; 8];
+    assert_eq!(b.read(&mut buf).unwrap(), 3);
+    assert_eq!(&buf[..3], b"abc");
+    let c = Transport::try_clone(&a).unwrap();
+    drop(a);
+    b.write_all(b"d").unwrap();
+    drop(c);
+    assert_eq!(b.read(&mut buf).unwrap(), 0);
+    assert!(b.write_all(b"e").is_err());
+}
+
+#[cfg(feature = "tls")]
+#[test]
+fn test_tls() {
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+
+    #[fmt_function]
+    fn secret() -> String {
+        String::from("swordfish")
+    }
+
+    // a self-signed ca signing the certificates of the server and a client
+    let dir = std::env::temp_dir().join(format!("lrpc-tls-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = |name: &str, pem: String| {
+        let path = dir.join(name).to_str().unwrap().to_string();
+        std::fs::write(&path, pem).unwrap();
+        path
+    };
+    let ca_key = KeyPair::generate().unwrap();
+    let mut params = CertificateParams::new(Vec::new()).unwrap();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = params.self_signed(&ca_key).unwrap();
+    let ca_pem = file("ca.pem", ca.pem());
+    let signed = |name: &str| {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![String::from("localhost")]).unwrap();
+        params.distinguished_name.push(DnType::CommonName, name);
+        let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+        (
+            file(&format!("{}.pem", name), cert.pem()),
+            file(&format!("{}.key", name), key.serialize_pem()),
+        )
+    };
+    let (srv_cert, srv_key) = signed("server");
+    let (cli_cert, cli_key) = signed("client");
+
+    let mut fun = Fun::new();
+    fun.regist("secret", secret);
+    let config = tls_server_config(&srv_cert, &srv_key, None).unwrap();
+    let (listener, one_way) = bind();
+    std::thread::spawn(move || service_on(fun, TlsListener::new(listener, config)));
+    let mut fun = Fun::new();
+    fun.regist("secret", secret);
+    fun.regist("admin", secret);
+    fun.allow("tls:CN=client", &["secret"]);
+    let config = tls_server_config(&srv_cert, &srv_key, Some(&ca_pem)).unwrap();
+    let (listener, mutual) = bind();
+    std::thread::spawn(move || service_on(fun, TlsListener::new(listener, config)));
+
+    let config = tls_client_config(&ca_pem, None).unwrap();
+    let mut con = Connection::new_tls(&one_way, "localhost", config.clone());
+    for _ in 0..3 {
+        let rst: Result<String> = con.invoke(fun!("secret"));
+        assert_eq!(rst, Ok(String::from("swordfish")));
+    }
+    let mut con = Connection::new_tls(&one_way, "example.com", config.clone());
+    assert!(con.invoke::<String>(fun!("secret")).is_err());
+
+    let mut con = Connection::new_tls(&mutual, "localhost", config);
+    assert!(con.invoke::<String>(fun!("secret")).is_err());
+    let config = tls_client_config(&ca_pem, Some((&cli_cert, &cli_key))).unwrap();
+    let mut con = Connection::new_tls(&mutual, "localhost", config);
+    let rst: Result<String> = con.invoke(fun!("secret"));
+    assert_eq!(rst, Ok(String::from("swordfish")));
+    let rst: Result<String> = con.invoke(fun!("admin"));
+    assert_eq!(
+        rst,
+        Err(String::from("permission denied to call admin function"))
+    );
+
+    // pools and balancers connect with dial_tls
+    let config = tls_client_config(&ca_pem, None).unwrap();
+    let c = config.clone();
+    let addr = one_way.clone();
+    let pool = Pool::with(move || Connection::dial_tls(&addr, "localhost", c.clone()));
+    assert_eq!(pool.invoke(fun!("secret")), Ok(String::from("swordfish")));
+    let c = config.clone();
+    let lb = Balancer::new(&[&one_way], Strategy::RoundRobin)
+        .connect(move |addr| Connection::dial_tls(addr, "localhost", c.clone()));
+    assert_eq!(lb.invoke(fun!("secret")), Ok(String::from("swordfish")));
+    let (listener, closed) = bind();
+    drop(listener);
+    assert!(Connection::dial_tls(&closed, "localhost", config.clone()).is_err());
+    assert!(Connection::dial_tls(&one_way, "", config).is_err());
+
+    // a client that never completes the handshake is dropped
+    let mut raw = std::net::TcpStream::connect(&one_way).unwrap();
+    let mut b = [0; 16];
+    assert_eq!(std::io::Read::read(&mut raw, &mut b).unwrap(), 0);
+
+    assert!(tls_server_config(&ca_pem, &ca_pem, None).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "psk")]
+#[test]
+fn test_psk() {
+    #[fmt_function]
+    fn hello() -> String {
+        String::from("hi")
+    }
+
+    let mut fun = Fun::new();
+    fun.regist("hello", hello);
+    fun.psk(b"open sesame");
+    let addr = serve_local(fun);
+    let mut fun = Fun::new();
+    fun.regist("hello", hello);
+    let open = serve_local(fun);
+
+    let mut con = Connection::new(&addr);
+    assert_eq!(con.authenticate(b"open sesame"), Ok(()));
+    let rst: Result<String> = con.invoke(fun!("hello"));
+    assert_eq!(rst, Ok(String::from("hi")));
+
+    let mut con = Connection::new(&addr);
+    assert_eq!(
+        con.authenticate(b"open barley"),
+        Err(String::from("the server does not know the key"))
+    );
+    let mut con = Connection::new(&addr);
+    assert!(con.invoke::<String>(fun!("hello")).is_err());
+
+    // a frame too long for the handshake is not read, a silent caller
+    // is disconnected once the handshake takes too long
+    use std::io::{Read, Write};
+    let mut s = std::net::TcpStream::connect(&addr).unwrap();
+    s.write_all(&send_data(ByteQue::from(vec![0u8; 2000])))
+        .unwrap();
+    assert!(matches!(s.read(&mut [0u8; 16]), Ok(0) | Err(_)));
+    let mut s = std::net::TcpStream::connect(&addr).unwrap();
+    let start = std::time::Instant::now();
+    assert!(matches!(s.read(&mut [0u8; 16]), Ok(0) | Err(_)));
+    assert!(start.elapsed() >= std::time::Duration::from_secs(4));
+
+    let mut con = Connection::new(&open);
+    assert_eq!(
+        con.authenticate(b"open sesame"),
+        Err(String::from("the server does not require a key"))
+    );
+}
+
+#[test]
+fn test_acl() {
+    #[fmt_function]
+    fn get_name() -> String {
+        String::from("lrpc")
+    }
+
+    #[fmt_function]
+    fn set_name(_: String) {}
+
+    #[fmt_function]
+    fn ping() {}
+
+    let new_fun = || {
+        let mut fun = Fun::new();
+        fun.regist("get_name", get_name);
+        fun.regist("set_name", set_name);
+        fun.regist("ping", ping);
+        fun.allow("*", &["ping"]);
+        fun
+    };
+    let denied = |name: &str| Err(format!("permission denied to call {} function", name));
+    // a denied caller cannot tell which functions exist
+    let mut con = Connection::loopback(new_fun());
+    assert_eq!(con.invoke(fun!("ping")), Ok(()));
+    assert_eq!(con.invoke::<()>(fun!("get_name")), denied("get_name"));
+    assert_eq!(con.invoke::<()>(fun!("get_nothing")), denied("get_nothing"));
+    let id = FunId::new("get_name");
+    assert_eq!(
+        con.invoke::<String>(fun_id!(id)),
+        Err(format!("function id {} not found", id.0))
+    );
+
+    // topics are denied too once there are rules
+    let denied_topic =
+        |topic: &str| Err(format!("permission denied to subscribe to {} topic", topic));
+    let mut fun = new_fun();
+    fun.pubsub(Hub::new(16, SlowConsumer::DropOldest));
+    let mut con = Connection::loopback(fun);
+    assert_eq!(con.subscribe("news"), denied_topic("news"));
+    let mut fun = new_fun();
+    fun.pubsub(Hub::new(16, SlowConsumer::DropOldest));
+    fun.allow_topics("*", &["news.*"]);
+    let mut con = Connection::loopback(fun);
+    assert_eq!(con.subscribe("news.sport"), Ok(()));
+    assert_eq!(con.subscribe("prices"), denied_topic("prices"));
+    assert_eq!(con.unsubscribe("prices"), denied_topic("prices"));
+
+    #[cfg(feature = "psk")]
+    {
+        let mut fun = new_fun();
+        fun.psk_id("reader", b"r");
+        fun.psk_id("writer", b"w");
+        fun.allow("psk:reader", &["get_*"]);
+        fun.allow("psk:writer", &["get_*", "set_name"]);
+        let addr = serve_local(fun);
+
+        let mut con = Connection::new(&addr);
+        assert_eq!(
+            con.authenticate_as("reader", b"w"),
+            Err(String::from("the server does not know the key"))
+        );
+        let mut con = Connection::new(&addr);
+        con.authenticate_as("reader", b"r").unwrap();
+        assert_eq!(con.invoke(fun!("get_name")), Ok(String::from("lrpc")));
+        assert_eq!(con.invoke(fun!("ping")), Ok(()));
+        assert_eq!(
+            con.invoke::<()>(fun!("set_name", String::new())),
+            denied("set_name")
+        );
+        assert_eq!(
+            con.batch()
+                .call(fun!("set_name", String::new()))
+                .send::<()>(),
+            Ok(vec![denied("set_name")])
+        );
+        let mut con = Connection::new(&addr);
+        con.authenticate_as("writer", b"w").unwrap();
+        assert_eq!(con.invoke(fun!("set_name", String::new())), Ok(()));
+    }
+
+    // unix sockets know the user id of the peer
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let path = std::env::temp_dir().join(format!("lrpc-acl-{}.sock", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let mut fun = new_fun();
+        fun.allow(&format!("uid:{}", unsafe { libc::getuid() }), &["get_name"]);
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        std::thread::spawn(move || service_on(fun, listener));
+        let mut con = Connection::connect_unix(&path);
+        assert_eq!(con.invoke(fun!("get_name")), Ok(String::from("lrpc")));
+        assert_eq!(
+            con.invoke::<()>(fun!("set_name", String::new())),
+            denied("set_name")
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
+
+#[test]
+fn test_context() {
+    #[fmt_function]
+    fn who(prefix: String, ctx: Context) -> String {
+        format!("{}{}", prefix, ctx.peer)
+    }
+
+    #[fmt_function]
+    fn ids(ctx: crate::Context) -> (u64, u64) {
+        (ctx.connection, ctx.request)
+    }
+
+    #[fmt_function]
+    fn deadline(c: Context) -> Option<u64> {
+        c.deadline
+            .map(|d| (d - std::time::Instant::now()).as_secs())
+    }
+
+    let mut fun = Fun::new();
+    fun.regist("who", who);
+    fun.regist("ids", ids);
+    fun.regist("deadline", deadline);
+    let r = Result::<String>::restore(
+        &mut fun.invoke_from("here", &mut fun!("who", "at ".to_string())),
+    );
+    assert_eq!(r, Ok("at here".to_string()));
+    assert_eq!(context().request, 0);
+
+    let mut con = Connection::loopback(fun);
+    let rst: Result<String> = con.invoke(fun!("who", "at ".to_string()));
+    assert_eq!(rst, Ok("at loopback".to_string()));
+    let (c1, r1): (u64, u64) = con.invoke(fun!("ids")).unwrap();
+    let (c2, r2): (u64, u64) = con.invoke(fun!("ids")).unwrap();
+    assert!(c1 > 0 && c1 == c2 && r2 > r1);
+    assert_eq!(con.invoke(fun!("deadline")), Ok(None::<u64>));
+    con.deadline(Some(std::time::Duration::from_secs(60)));
+    assert_eq!(con.invoke(fun!("deadline")), Ok(Some(59u64)));
+    con.deadline(Some(std::time::Duration::from_millis(u64::MAX)));
+    assert!(con.invoke::<Option<u64>>(fun!("deadline")).is_ok());
+
+    // deadlines nested deeper than the stack are refused, not recursed into
+    let mut fun = Fun::new();
+    fun.regist("ids", ids);
+    let (mut client, server) = pipe();
+    std::thread::spawn(move || crate::transport::serve_stream(&fun, server));
+    let mut q = fun!("ids");
+    for _ in 0..100_000 {
+        let mut d = ByteQue::new();
+        1000u64.store(&mut d);
+        d.push_slice(q.as_slice());
+        q = crate::frame::wrap(crate::frame::DEADLINE, d);
+    }
+    use std::io::Write;
+    client.write_all(&send_data(q)).unwrap();
+    assert_eq!(
+        Result::<()>::restore(&mut recv_data(&mut client).unwrap()),
+        Err(String::from("a call has only one deadline"))
+    );
+    client.write_all(&send_data(fun!("ids"))).unwrap();
+    assert!(Result::<(u64, u64)>::restore(&mut recv_data(&mut client).unwrap()).is_ok());
+}
+
+#[test]
+fn test_session() {
+    use std::sync::mpsc;
+
+    #[derive(Clone)]
+    struct User(String);
+
+    #[fmt_function]
+    fn login(name: String, ctx: Context) {
+        ctx.session.insert(User(name));
+    }
+
+    #[fmt_function]
+    fn whoami(ctx: Context) -> Result<String> {
+        match ctx.session.get::<User>() {
+            Some(User(name)) => Ok(name),
+            None => Err(String::from("not logged in")),
+        }
+    }
+
+    #[fmt_function]
+    fn calls(ctx: Context) -> u32 {
+        ctx.session.with(|n: Option<&mut u32>| {
+            let n = n.unwrap();
+            *n += 1;
+            *n
+        })
+    }
+
+    let (tx, gone) = mpsc::channel();
+    let new_fun = || {
+        let mut fun = Fun::new();
+        fun.regist("login", login);
+        fun.regist("whoami", whoami);
+        fun.regist("calls", calls);
+        fun.on_connect(|ctx| {
+            ctx.session.insert(0u32);
+        });
+        let tx = tx.clone();
+        fun.on_disconnect(move |ctx| {
+            tx.send(ctx.connection).unwrap();
+        });
+        fun
+    };
+    let mut alice = Connection::loopback(new_fun());
+    let mut bob = Connection::loopback(new_fun());
+    assert_eq!(alice.invoke(fun!("login", "alice".to_string())), Ok(()));
+    assert_eq!(alice.invoke(fun!("whoami")), Ok("alice".to_string()));
+    assert_eq!(
+        bob.invoke::<String>(fun!("whoami")),
+        Err("not logged in".to_string())
+    );
+    assert_eq!(alice.invoke(fun!("calls")), Ok(1u32));
+    assert_eq!(alice.invoke(fun!("calls")), Ok(2u32));
+    assert_eq!(bob.invoke(fun!("calls")), Ok(1u32));
+
+    drop(alice);
+    drop(bob);
+    let timeout = std::time::Duration::from_secs(5);
+    let a = gone.recv_timeout(timeout).unwrap();
+    let b = gone.recv_timeout(timeout).unwrap();
+    assert!(a > 0 && b > 0 && a != b);
+
+    // a panicking on_connect closes the connection,
+    // a panicking on_disconnect does not take the thread down
+    let mut fun = new_fun();
+    fun.on_connect(|_| panic!("no sessions today"));
+    let mut con = Connection::loopback(fun);
+    assert!(con.invoke::<u32>(fun!("calls")).is_err());
+    let mut fun = Fun::new();
+    fun.on_disconnect(|_| panic!("too late"));
+    let (client, server) = pipe();
+    drop(client);
+    crate::transport::serve_stream(&fun, server);
+
+    let s = Session::default();
+    assert_eq!(s.insert(1i32), None);
+    assert_eq!(s.insert(2i32), Some(1));
+    assert_eq!(s.remove::<i32>(), Some(2));
+    assert_eq!(s.get::<i32>(), None);
+}
+
+#[test]
+fn test_limit() {
+    use std::sync::{
+        mpsc::{self, Receiver, Sender},
+        Mutex, OnceLock,
+    };
+
+    // tells the test it runs, then waits to be let go
+    type Gate = (Mutex<Sender<()>>, Mutex<Receiver<()>>);
+    static GATE: OnceLock<Gate> = OnceLock::new();
+
+    #[fmt_function]
+    fn cheap() {}
+
+    #[fmt_function]
+    fn slow() {
+        let (started, release) = GATE.get().unwrap();
+        let _ = started.lock().unwrap().send(());
+        let _ = release.lock().unwrap().recv();
+    }
+
+    let exhausted = |name: &str| {
+        Err(format!(
+            "resource exhausted: {} function is called too often",
+            name
+        ))
+    };
+    let mut fun = Fun::new();
+    fun.regist("cheap", cheap);
+    fun.regist("slow", slow);
+    fun.limit("cheap", Limit::new().rate(0.0, 2).per_peer());
+    fun.limit("slow", Limit::new().in_flight(1));
+    let call = |fun: &Fun, peer: &str, name: &str| {
+        Result::<()>::restore(&mut fun.invoke_from(peer, &mut fun!(name)))
+    };
+    assert_eq!(call(&fun, "a", "cheap"), Ok(()));
+    assert_eq!(call(&fun, "a", "cheap"), Ok(()));
+    assert_eq!(call(&fun, "a", "cheap"), exhausted("cheap"));
+    assert_eq!(call(&fun, "b", "cheap"), Ok(()));
+    // a new connection from the same host does not get a new allowance
+    assert_eq!(call(&fun, "10.0.0.1:4000", "cheap"), Ok(()));
+    assert_eq!(call(&fun, "10.0.0.1:4001", "cheap"), Ok(()));
+    assert_eq!(call(&fun, "10.0.0.1:4002", "cheap"), exhausted("cheap"));
+
+    // a call refused by one rule is not counted by the others
+    let mut both = Fun::new();
+    both.regist("cheap", cheap);
+    both.limit("cheap", Limit::new().rate(0.0, 2));
+    both.limit("che*", Limit::new().rate(0.0, 1).per_peer());
+    assert_eq!(call(&both, "a", "cheap"), Ok(()));
+    assert_eq!(call(&both, "a", "cheap"), exhausted("cheap"));
+    assert_eq!(call(&both, "b", "cheap"), Ok(()));
+    assert_eq!(call(&both, "c", "cheap"), exhausted("cheap"));
+
+    let (tx, started) = mpsc::channel();
+    let (release, rx) = mpsc::channel();
+    GATE.set((Mutex::new(tx), Mutex::new(rx))).unwrap();
+    let fun = std::sync::Arc::new(fun);
+    let f = fun.clone();
+    let running = std::thread::spawn(move || call(&f, "a", "slow"));
+    started.recv().unwrap();
+    assert_eq!(
+        call(&fun, "b", "slow"),
+        Err("resource exhausted: slow function has too many calls running".to_string())
+    );
+    // the calls after this are not held
+    drop(release);
+    assert_eq!(running.join().unwrap(), Ok(()));
+    assert_eq!(call(&fun, "b", "slow"), Ok(()));
+}
+
+#[test]
+fn test_retry() {
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+        sync::atomic::{AtomicU32, Ordering},
+        thread,
+    };
+
+    static COUNTED: AtomicU32 = AtomicU32::new(0);
+    static CHARGED: AtomicU32 = AtomicU32::new(0);
+
+    #[fmt_function]
+    fn count() -> u32 {
+        COUNTED.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    #[fmt_function]
+    fn charge() -> u32 {
+        CHARGED.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    // every other connection loses the first request or its reply
+    let proxy = |upstream: &str, lose_reply: bool| {
+        let (l, addr) = bind();
+        let upstream = upstream.to_string();
+        thread::spawn(move || {
+            for (i, mut c) in l.incoming().flatten().enumerate() {
+                let mut up = TcpStream::connect(&upstream).unwrap();
+                if i % 2 == 0 {
+                    let mut buf = [0; 1024];
+                    let n = c.read(&mut buf).unwrap();
+                    if lose_reply {
+                        up.write_all(&buf[..n]).unwrap();
+                        let _ = up.read(&mut buf).unwrap();
+                    }
+                    continue;
+                }
+                let (mut c2, mut up2) = (c.try_clone().unwrap(), up.try_clone().unwrap());
+                thread::spawn(move || std::io::copy(&mut c2, &mut up2));
+                thread::spawn(move || std::io::copy(&mut up, &mut c));
+            }
+        });
+        addr
+    };
+
+    let mut fun = Fun::new();
+    fun.regist_idempotent("count", count);
+    fun.regist("charge", charge);
+    let addr = serve_local(fun);
+    let lose_reply = proxy(&addr, true);
+    let lose_request = proxy(&addr, false);
+
+    let policy = Retry::new(3).backoff(
+        std::time::Duration::from_millis(1),
+        std::time::Duration::from_millis(5),
+    );
+    // the reply is lost, the retry gets it without charging again
+    let mut con = Connection::new(&lose_reply);
+    con.retry(policy);
+    assert_eq!(con.invoke(fun!("charge")), Ok(1u32));
+    assert_eq!(CHARGED.load(Ordering::SeqCst), 1);
+    assert_eq!(con.invoke(fun!("charge")), Ok(2u32));
+
+    // the request is lost, only the idempotent function runs again
+    let mut con = Connection::new(&lose_request);
+    con.retry(policy);
+    assert_eq!(con.invoke(fun!("count")), Ok(1u32));
+    let mut con = Connection::new(&lose_request);
+    con.retry(policy);
+    assert_eq!(
+        con.invoke::<u32>(fun!("charge")),
+        Err(String::from(
+            "charge function is not idempotent and may have run before the connection was lost"
+        ))
+    );
+    assert_eq!(CHARGED.load(Ordering::SeqCst), 2);
+
+    // without a policy the error is returned
+    let mut con = Connection::new(&lose_request);
+    assert!(con.invoke::<u32>(fun!("count")).is_err());
+
+    // replies are kept by caller, the same key from another caller runs again
+    let mut fun = Fun::new();
+    fun.regist("charge", charge);
+    let keyed = |identity: &str, addr: &str| {
+        let peer = crate::fun::Peer {
+            addr,
+            identity,
+            ..Default::default()
+        };
+        Result::<u32>::restore(&mut fun.invoke_keyed(peer, 7, 0, &mut fun!("charge"))).unwrap()
+    };
+    let a = keyed("psk:a", "10.0.0.1:1000");
+    assert_eq!(keyed("psk:a", "10.0.0.2:2000"), a);
+    assert_ne!(keyed("psk:b", "10.0.0.1:1000"), a);
+    let c = keyed("", "10.0.0.3:1000");
+    assert_eq!(keyed("", "10.0.0.3:2000"), c);
+    assert_ne!(keyed("", "10.0.0.4:1000"), c);
+}
+
+#[test]
+fn test_pool() {
+    use std::{
+        collections::HashSet,
+        sync::{Barrier, OnceLock},
+        time::Duration,
+    };
+
+    // the calls run two at a time
+    static PAIRS: OnceLock<Barrier> = OnceLock::new();
+
+    #[fmt_function]
+    fn conn_id(ctx: Context) -> u64 {
+        ctx.connection
+    }
+
+    #[fmt_function]
+    fn paired(ctx: Context) -> u64 {
+        PAIRS.get_or_init(|| Barrier::new(2)).wait();
+        ctx.connection
+    }
+
+    let mut fun = Fun::new();
+    fun.regist("conn_id", conn_id);
+    fun.regist("paired", paired);
+    let addr = serve_local(fun);
+
+    let pool = Pool::new(&addr).min(1).max(2);
+    let ids: HashSet<u64> = std::thread::scope(|s| {
+        let calls: Vec<_> = (0..6)
+            .map(|_| s.spawn(|| pool.invoke::<u64>(fun!("paired")).unwrap()))
+            .collect();
+        calls.into_iter().map(|c| c.join().unwrap()).collect()
+    });
+    assert_eq!(ids.len(), 2);
+    assert_eq!(pool.size(), (2, 2));
+
+    // idle connections are closed down to min
+    let pool = Pool::new(&addr).min(1).max(2).idle_timeout(Duration::ZERO);
+    let (a, mut b) = (pool.get().unwrap(), pool.get().unwrap());
+    // the oldest goes first
+    let id: u64 = b.invoke(fun!("conn_id")).unwrap();
+    drop((a, b));
+    let mut conn = pool.get().unwrap();
+    assert_eq!(pool.size(), (1, 0));
+    assert_eq!(conn.invoke(fun!("conn_id")), Ok(id));
+    drop(conn);
+
+    // a connection failing its check is replaced
+    let pool = Pool::new(&addr).health_check(|_| false);
+    let first: u64 = pool.invoke(fun!("conn_id")).unwrap();
+    let second: u64 = pool.invoke(fun!("conn_id")).unwrap();
+    assert_ne!(first, second);
+    assert_eq!(pool.size(), (1, 1));
+
+    let pool = Pool::new("127.0.0.1:1");
+    assert!(pool.invoke::<u64>(fun!("conn_id")).is_err());
+    assert_eq!(pool.size(), (0, 0));
+}
+
+#[test]
+fn test_balance() {
+    use std::{collections::HashMap, time::Duration};
+
+    #[fmt_function]
+    fn first() -> u8 {
+        1
+    }
+    #[fmt_function]
+    fn second() -> u8 {
+        2
+    }
+
+    let mut fun = Fun::new();
+    fun.regist("server", first);
+    let a = serve_local(fun);
+    let mut fun = Fun::new();
+    fun.regist("server", second);
+    let b = serve_local(fun);
+    let addrs = [a.as_str(), b.as_str(), "127.0.0.1:1"];
+
+    let lb = Balancer::new(&addrs, Strategy::RoundRobin);
+    let mut seen = HashMap::new();
+    for _ in 0..6 {
+        let server: u8 = lb.invoke(fun!("server")).unwrap();
+        *seen.entry(server).or_insert(0) += 1;
+    }
+    assert_eq!(seen.len(), 2);
+    let mut up = vec![a.clone(), b.clone()];
+    up.sort();
+    assert_eq!(lb.healthy(), up);
+
+    let lb = Balancer::new(&addrs, Strategy::LeastOutstanding);
+    let server: u8 = lb.invoke(fun!("server")).unwrap();
+    assert!(server == 1 || server == 2);
+
+    // a key keeps going to the same server while it is up,
+    // the server that is down is tried again at once
+    let lb = Balancer::new(&addrs, Strategy::ConsistentHash).eject_for(Duration::ZERO);
+    for key in ["alice", "bob", "carol"] {
+        let first: u8 = lb.invoke_key(key, fun!("server")).unwrap();
+        for _ in 0..3 {
+            assert_eq!(lb.invoke_key::<u8>(key, fun!("server")).unwrap(), first);
+        }
+    }
+    assert_eq!(lb.healthy().len(), 3);
+
+    let lb = Balancer::new(&["127.0.0.1:1"], Strategy::RoundRobin);
+    assert!(lb.invoke::<u8>(fun!("server")).is_err());
+}
+
+#[test]
+fn test_breaker() {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    #[fmt_function]
+    fn ping() -> bool {
+        true
+    }
+
+    let mut fun = Fun::new();
+    fun.regist("ping", ping);
+    let addr = serve_local(fun);
+
+    let up = Arc::new(AtomicBool::new(false));
+    let pool = |breaker: &Breaker| {
+        let dial = up.clone();
+        let addr = addr.clone();
+        Pool::with(move || match dial.load(Ordering::SeqCst) {
+            true => Connection::dial(&addr),
+            false => Connection::dial("127.0.0.1:1"),
+        })
+        .breaker(breaker.clone())
+    };
+
+    let breaker = Breaker::new()
+        .error_rate(0.5, 2)
+        .open_for(Duration::from_secs(60));
+    let p = pool(&breaker);
+    for _ in 0..2 {
+        assert!(p.invoke::<bool>(fun!("ping")).is_err());
+    }
+    assert_eq!(breaker.state(), Circuit::Open);
+    assert_eq!(
+        p.invoke::<bool>(fun!("ping")),
+        Err(String::from("the circuit breaker is open"))
+    );
+
+    // half-open at once, a failed trial opens it again
+    let breaker = Breaker::new().error_rate(0.5, 2).open_for(Duration::ZERO);
+    let pool = pool(&breaker);
+    for _ in 0..2 {
+        assert!(pool.invoke::<bool>(fun!("ping")).is_err());
+    }
+    assert_eq!(breaker.state(), Circuit::HalfOpen);
+    assert!(pool.invoke::<bool>(fun!("ping")).is_err());
+    assert_eq!(breaker.state(), Circuit::HalfOpen);
+
+    up.store(true, Ordering::SeqCst);
+    assert_eq!(pool.invoke(fun!("ping")), Ok(true));
+    assert_eq!(breaker.state(), Circuit::Closed);
+    assert_eq!(breaker.counts(), (0, 0));
+    assert_eq!(pool.invoke(fun!("ping")), Ok(true));
+    assert_eq!(breaker.counts(), (1, 0));
+
+    // errors returned by the functions are failures unless transport_only,
+    // every kind of call goes through the breaker
+    #[fmt_function]
+    fn fail() -> Result<bool> {
+        Err(String::from("down for maintenance"))
+    }
+
+    fn ticks(q: &mut ByteQue, tx: &mut Sender) -> Result<()> {
+        for i in 0..u32::restore(q) {
+            tx.send(&i)?;
+        }
+        Err(String::from("out of ticks"))
+    }
+
+    let new_fun = || {
+        let mut fun = Fun::new();
+        fun.regist("ping", ping);
+        fun.regist("fail", fail);
+        fun.regist_stream("ticks", ticks);
+        fun
+    };
+    let open = Err(String::from("the circuit breaker is open"));
+    let breaker = Breaker::new()
+        .error_rate(0.5, 2)
+        .open_for(Duration::from_secs(60));
+    let mut con = Connection::loopback(new_fun());
+    con.breaker(breaker.clone());
+    assert_eq!(con.invoke(fun!("ping")), Ok(true));
+    assert!(con.invoke::<bool>(fun!("fail")).is_err());
+    assert_eq!(breaker.state(), Circuit::Open);
+    assert_eq!(con.invoke::<bool>(fun!("ping")), open);
+    assert_eq!(
+        con.batch().call(fun!("ping")).send::<bool>().err(),
+        open.clone().err()
+    );
+    assert_eq!(
+        con.stream::<u32>(fun!("ticks", 2u32)).err(),
+        open.clone().err()
+    );
+    assert_eq!(con.notify(fun!("ping")), open.map(|_: bool| ()));
+
+    let breaker = Breaker::new()
+        .error_rate(0.5, 2)
+        .open_for(Duration::from_secs(60));
+    let mut con = Connection::loopback(new_fun());
+    con.breaker(breaker.clone());
+    let r = con
+        .batch()
+        .call(fun!("ping"))
+        .call(fun!("fail"))
+        .send::<bool>();
+    assert!(r.unwrap()[1].is_err());
+    let items: Vec<Result<u32>> = con.stream(fun!("ticks", 2u32)).unwrap().collect();
+    assert_eq!(items.len(), 3);
+    assert_eq!(breaker.state(), Circuit::Open);
+
+    let breaker = Breaker::new()
+        .error_rate(0.5, 2)
+        .open_for(Duration::from_secs(60))
+        .transport_only();
+    let mut con = Connection::loopback(new_fun());
+    con.breaker(breaker.clone());
+    for _ in 0..4 {
+        assert!(con.invoke::<bool>(fun!("fail")).is_err());
+    }
+    assert_eq!(breaker.state(), Circuit::Closed);
+    assert_eq!(breaker.counts(), (4, 0));
+}
+
+#[test]
+fn test_keepalive() {
+    use std::{
+        io::{Read, Write},
+        sync::mpsc,
+        time::Duration,
+    };
+
+    #[fmt_function]
+    fn slow() -> bool {
+        std::thread::sleep(Duration::from_millis(200));
+        true
+    }
+
+    let new_fun = |gone: mpsc::Sender<()>| {
+        let mut fun = Fun::new();
+        fun.regist("slow", slow);
+        fun.keepalive(Duration::from_millis(20));
+        fun.on_disconnect(move |_| {
+            let _ = gone.send(());
+        });
+        fun
+    };
+
+    // the server closes a client that sends nothing
+    let (tx, gone) = mpsc::channel();
+    let mut idle = Connection::loopback(new_fun(tx));
+    gone.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(idle.invoke::<bool>(fun!("slow")).is_err());
+    assert!(idle.is_broken());
+
+    // the heartbeats of the server keep a long call alive
+    let (tx, gone) = mpsc::channel();
+    let mut alive = Connection::loopback(new_fun(tx));
+    alive.keepalive(Duration::from_millis(20)).unwrap();
+    assert_eq!(alive.invoke(fun!("slow")), Ok(true));
+    assert_eq!(alive.invoke(fun!("slow")), Ok(true));
+    assert!(gone.try_recv().is_err());
+
+    // an idle client notices a server that stops answering after a heartbeat
+    let (client, mut server) = crate::mem::pipe();
+    let (tx, gone) = mpsc::channel();
+    std::thread::spawn(move || {
+        server.write_all(&[0]).unwrap();
+        let mut b = [0; 64];
+        while server.read(&mut b).unwrap_or(0) > 0 {}
+        tx.send(()).unwrap();
+    });
+    let mut conn = Connection::from_transport(client).unwrap();
+    conn.keepalive(Duration::from_millis(10)).unwrap();
+    gone.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(conn.invoke::<bool>(fun!("slow")).is_err());
+    assert!(conn.is_broken());
+
+    let mut conn = Connection::from_stream(std::io::Cursor::new(Vec::new()));
+    assert!(conn.keepalive(Duration::from_millis(10)).is_err());
+
+    // a rejected key ends the handshake at once
+    #[cfg(feature = "psk")]
+    {
+        let (tx, _gone) = mpsc::channel();
+        let mut fun = new_fun(tx);
+        fun.psk(b"open sesame");
+        let mut con = Connection::loopback(fun);
+        con.keepalive(Duration::from_millis(10)).unwrap();
+        assert_eq!(
+            con.authenticate(b"open barley"),
+            Err(String::from("the server does not know the key"))
+        );
+        assert!(con.invoke::<bool>(fun!("slow")).is_err());
+
+        let (tx, _gone) = mpsc::channel();
+        let mut fun = new_fun(tx);
+        fun.psk(b"open sesame");
+        let mut con = Connection::loopback(fun);
+        con.keepalive(Duration::from_millis(10)).unwrap();
+        assert_eq!(con.authenticate(b"open sesame"), Ok(()));
+        assert_eq!(con.invoke(fun!("slow")), Ok(true));
+    }
+}
+
+#[test]
+fn test_keepalive_ping() {
+    use std::{
+        io::{self, Read, Write},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            mpsc, Arc,
+        },
+        time::Duration,
+    };
+
+    #[fmt_function]
+    fn slow() -> bool {
+        std::thread::sleep(Duration::from_millis(200));
+        true
+    }
+
+    // the server side of a pipe whose replies can be lost,
+    // as when the network between them fails
+    struct Lossy(Pipe, Arc<AtomicBool>);
+
+    impl Read for Lossy {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for Lossy {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.1.load(Ordering::SeqCst) {
+                return Ok(buf.len());
+            }
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    impl Transport for Lossy {
+        fn try_clone(&self) -> io::Result<Self> {
+            Ok(Lossy(self.0.try_clone()?, self.1.clone()))
+        }
+
+        fn close(&self) {
+            Transport::close(&self.0)
+        }
+    }
+
+    // a server without keepalive sends heartbeats once pinged,
+    // a long call is kept alive and a lost server is noticed while idle
+    let (tx, gone) = mpsc::channel();
+    let mut fun = Fun::new();
+    fun.regist("slow", slow);
+    fun.on_disconnect(move |_| {
+        let _ = tx.send(());
+    });
+    let (client, server) = pipe();
+    let lost = Arc::new(AtomicBool::new(false));
+    let server = Lossy(server, lost.clone());
+    std::thread::spawn(move || crate::transport::serve_stream(&fun, server));
+    let mut conn = Connection::from_transport(client).unwrap();
+    conn.keepalive(Duration::from_millis(20)).unwrap();
+    assert_eq!(conn.invoke(fun!("slow")), Ok(true));
+    assert_eq!(conn.invoke(fun!("slow")), Ok(true));
+    assert!(gone.try_recv().is_err());
+    lost.store(true, Ordering::SeqCst);
+    gone.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(conn.invoke::<bool>(fun!("slow")).is_err());
+    assert!(conn.is_broken());
+
+    // a zero interval is taken as the shortest one rather than closing
+    // the connection at once, calls outlasting it are left to the above
+    #[fmt_function]
+    fn quick() -> bool {
+        true
+    }
+    let mut fun = Fun::new();
+    fun.regist("quick", quick);
+    fun.keepalive(Duration::ZERO);
+    let mut conn = Connection::loopback(fun);
+    conn.keepalive(Duration::ZERO).unwrap();
+    assert_eq!(conn.invoke(fun!("quick")), Ok(true));
+    assert_eq!(conn.invoke(fun!("quick")), Ok(true));
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_async() {
+    use futures::{executor::block_on, future::join};
+
+    fn sum<'a>(_: ByteQue, rx: &'a mut AsyncReceiver) -> BoxFuture<'a, ByteQue> {
+        Box::pin(async move {
+            let mut n = 0u32;
+            while let Some(x) = rx.recv::<u32>().await {
+                n += x.unwrap();
+            }
+            let mut r = ByteQue::new();
+            Result::<u32>::Ok(n).store(&mut r);
+            r
+        })
+    }
+    fn double<'a>(_: ByteQue, d: &'a mut AsyncDuplex) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            while let Some(x) = d.recv::<i64>().await {
+                d.send(&(x? * 2)).await?;
+            }
+            Ok(())
+        })
+    }
+    fn flood<'a>(_: ByteQue, d: &'a mut AsyncDuplex) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            for i in 0..48i64 {
+                d.send(&i).await?;
+            }
+            let mut n = 0i64;
+            while let Some(x) = d.recv::<i64>().await {
+                n += x?;
+            }
+            match n {
+                1128 => Ok(()),
+                n => Err(format!("received {}", n)),
+            }
+        })
+    }
+    fn blocking_sum(_: &mut ByteQue, rx: &mut Receiver) -> ByteQue {
+        let mut n = 0;
+        while let Some(x) = rx.recv::<u32>() {
+            n += x.unwrap();
+        }
+        let mut r = ByteQue::new();
+        Result::<u32>::Ok(n).store(&mut r);
+        r
+    }
+    fn add(q: &mut ByteQue) -> ByteQue {
+        let mut r = ByteQue::new();
+        Result::<i32>::Ok(i32::restore(q) + i32::restore(q)).store(&mut r);
+        r
+    }
+    let mut fun = Fun::new();
+    fun.regist("add", add);
+    fun.regist_upload("blocking_sum", blocking_sum);
+    fun.regist_upload_async("sum", sum);
+    fun.service("math").regist_duplex_async("double", double);
+    fun.regist_duplex_async("flood", flood);
+
+    // client and service on one thread, neither may block it
+    let (mut client, server) = pipe();
+    let calls = async move {
+        let mut up = AsyncUpload::call(&mut client, fun!("sum")).await.unwrap();
+        for i in 0..100u32 {
+            up.send(&i).await.unwrap();
+        }
+        assert_eq!(up.finish::<u32>().await, Ok(4950));
+
+        let mut d = AsyncDuplex::call(&mut client, fun!("math.double"))
+            .await
+            .unwrap();
+        for i in 0..100i64 {
+            d.send(&i).await.unwrap();
+            assert_eq!(d.recv::<i64>().await, Some(Ok(i * 2)));
+        }
+        assert_eq!(d.finish().await, Ok(()));
+
+        // both sides send more than the window before reading
+        let mut d = AsyncDuplex::call(&mut client, fun!("flood")).await.unwrap();
+        for i in 0..48i64 {
+            d.send(&i).await.unwrap();
+        }
+        let mut n = 0;
+        for _ in 0..48 {
+            n += d.recv::<i64>().await.unwrap().unwrap();
+        }
+        assert_eq!(n, 1128);
+        assert_eq!(d.finish().await, Ok(()));
+
+        let up = AsyncUpload::call(&mut client, fun!("blocking_sum"))
+            .await
+            .unwrap();
+        assert_eq!(
+            up.finish::<u32>().await,
+            Err("blocking_sum function is an upload function".to_string())
+        );
+    };
+    // multi-threaded runtimes spawn Send futures only
+    fn spawnable<F: std::future::Future + Send>(f: F) -> F {
+        f
+    }
+    block_on(join(spawnable(serve_async(&fun, server)), spawnable(calls)));
+
+    // a blocking connection against serve_async
+    let (client, server) = pipe();
+    let fun = std::sync::Arc::new(fun);
+    let srv_fun = fun.clone();
+    let t = std::thread::spawn(move || block_on(serve_async(&srv_fun, server)));
+    let mut conn = Connection::from_transport(client).unwrap();
+    assert_eq!(conn.invoke(fun!("add", 1, 2)), Ok(3));
+    conn.notify(fun!("add", 1, 2)).unwrap();
+    let mut up = conn.upload(fun!("sum")).unwrap();
+    for i in 0..100u32 {
+        up.send(&i).unwrap();
+    }
+    assert_eq!(up.finish::<u32>(), Ok(4950));
+    let mut d = conn.duplex(fun!("math.double")).unwrap();
+    d.send(&21i64).unwrap();
+    assert_eq!(d.recv::<i64>(), Some(Ok(42)));
+    assert_eq!(d.finish(), Ok(()));
+    drop(conn);
+    t.join().unwrap();
+
+    // an async upload against a blocking service
+    let (mut client, server) = pipe();
+    let t = std::thread::spawn(move || crate::transport::serve_stream(&fun, server));
+    block_on(async {
+        let mut up = AsyncUpload::call(&mut client, fun!("blocking_sum"))
+            .await
+            .unwrap();
+        for i in 0..100u32 {
+            up.send(&i).await.unwrap();
+        }
+        assert_eq!(up.finish::<u32>().await, Ok(4950));
+    });
+    drop(client);
+    t.join().unwrap();
+}
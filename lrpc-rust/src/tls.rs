@@ -0,0 +1,289 @@
+//! Tls over tcp with rustls, enabled by the tls feature
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use lrpc::*;
+//!
+//! let config = tls_server_config("cert.pem", "key.pem", None).unwrap();
+//! let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+//! let addr = listener.local_addr().unwrap().to_string();
+//! std::thread::spawn(move || service_on(Fun::new(), TlsListener::new(listener, config)));
+//!
+//! let config = tls_client_config("ca.pem", None).unwrap();
+//! let mut conn = Connection::new_tls(&addr, "localhost", config);
+//! ```
+
+use crate::{
+    fun::{Fun, Result},
+    tcp::Connection,
+    transport::{service_on, Listener, Transport},
+};
+use rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+    server::WebPkiClientVerifier,
+    ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection,
+};
+use std::{
+    convert::TryFrom,
+    io::{self, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// How long the server waits for the client to complete the handshake
+const HANDSHAKE: Duration = Duration::from_secs(5);
+
+/// Like service, the connections are encrypted with tls
+pub fn service_tls(srv_fun: Fun, addr: &str, config: ServerConfig) {
+    service_on(
+        srv_fun,
+        TlsListener::new(TcpListener::bind(addr).unwrap(), config),
+    )
+}
+
+impl Connection {
+    /// Connect to service_tls, server_name is checked against its certificate
+    pub fn new_tls(addr: &str, server_name: &str, config: ClientConfig) -> Self {
+        Connection::dial_tls(addr, server_name, config).unwrap()
+    }
+
+    /// Like new_tls, returning the error instead of panicking
+    pub fn dial_tls(addr: &str, server_name: &str, config: ClientConfig) -> Result<Self> {
+        let name = ServerName::try_from(server_name.to_string()).map_err(|e| e.to_string())?;
+        let config = Arc::new(config);
+        let dial = move |addr: &str| -> io::Result<Tls> {
+            let conn =
+                ClientConnection::new(config.clone(), name.clone()).map_err(io::Error::other)?;
+            Ok(Tls::new(conn.into(), TcpStream::connect(addr)?))
+        };
+        let addr = addr.to_string();
+        let tls = dial(&addr).map_err(|e| e.to_string())?;
+        Ok(Connection::from_transport(tls)
+            .map_err(|e| e.to_string())?
+            .redial(move || dial(&addr)))
+    }
+}
+
+/// Config of service_tls, loading the certificate chain and the key from pem files.
+/// With client_ca the clients must have a certificate it signed
+pub fn tls_server_config(cert: &str, key: &str, client_ca: Option<&str>) -> Result<ServerConfig> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?;
+    let builder = match client_ca {
+        Some(ca) => {
+            let roots = Arc::new(roots(ca)?);
+            let verifier = WebPkiClientVerifier::builder_with_provider(roots, provider)
+                .build()
+                .map_err(|e| e.to_string())?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    builder
+        .with_single_cert(certs(cert)?, private_key(key)?)
+        .map_err(|e| e.to_string())
+}
+
+/// Config of Connection::new_tls, trusting the certificates in the ca pem file.
+/// identity is the certificate and key files the client authenticates with
+pub fn tls_client_config(ca: &str, identity: Option<(&str, &str)>) -> Result<ClientConfig> {
+    let provider: Arc<CryptoProvider> = Arc::new(ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_root_certificates(roots(ca)?);
+    match identity {
+        Some((cert, key)) => builder
+            .with_client_auth_cert(certs(cert)?, private_key(key)?)
+            .map_err(|e| e.to_string()),
+        None => Ok(builder.with_no_client_auth()),
+    }
+}
+
+fn certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|i| i.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| format!("{}: {}", path, e))?;
+    if certs.is_empty() {
+        return Err(format!("{}: no certificates", path));
+    }
+    Ok(certs)
+}
+
+fn private_key(path: &str) -> Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(|e| format!("{}: {}", path, e))
+}
+
+fn roots(path: &str) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in certs(path)? {
+        roots.add(cert).map_err(|e| format!("{}: {}", path, e))?;
+    }
+    Ok(roots)
+}
+
+/// Accepts tls connections, the handshake is done by the connection thread
+pub struct TlsListener {
+    tcp: TcpListener,
+    config: Arc<ServerConfig>,
+}
+
+impl TlsListener {
+    pub fn new(tcp: TcpListener, config: ServerConfig) -> Self {
+        TlsListener {
+            tcp,
+            config: Arc::new(config),
+        }
+    }
+}
+
+impl Listener for TlsListener {
+    type Stream = Tls;
+
+    fn accept(&self) -> Option<Tls> {
+        loop {
+            let sock = Listener::accept(&self.tcp)?;
+            if let Ok(conn) = ServerConnection::new(self.config.clone()) {
+                return Some(Tls::new(conn.into(), sock));
+            }
+        }
+    }
+}
+
+/// A tls stream that can be read by one thread while others write it,
+/// the socket is read without holding the lock of the tls state
+pub struct Tls {
+    conn: Arc<Mutex<rustls::Connection>>,
+    sock: TcpStream,
+}
+
+impl Tls {
+    fn new(mut conn: rustls::Connection, sock: TcpStream) -> Self {
+        conn.set_buffer_limit(None);
+        Tls {
+            conn: Arc::new(Mutex::new(conn)),
+            sock,
+        }
+    }
+
+    /// Send what the tls state has to send, handshake messages included
+    fn flush_tls(&self, conn: &mut rustls::Connection) -> io::Result<()> {
+        while conn.wants_write() {
+            conn.write_tls(&mut &self.sock)?;
+        }
+        Ok(())
+    }
+}
+
+impl Tls {
+    /// Read the socket once and process what came, without holding the lock
+    /// while waiting. Returns how many bytes came, 0 at the end
+    fn read_tls(&self) -> io::Result<usize> {
+        let mut raw = [0; 4096];
+        let n = (&self.sock).read(&mut raw)?;
+        let mut conn = self.conn.lock().unwrap();
+        let mut rd = &raw[..n];
+        while !rd.is_empty() {
+            conn.read_tls(&mut rd)?;
+            if let Err(e) = conn.process_new_packets() {
+                let _ = self.flush_tls(&mut conn);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+        }
+        self.flush_tls(&mut conn)?;
+        Ok(n)
+    }
+
+    /// Read and write until the handshake is done, false if it failed
+    fn handshake(&self) -> bool {
+        loop {
+            let mut conn = self.conn.lock().unwrap();
+            if !conn.is_handshaking() {
+                return true;
+            }
+            if self.flush_tls(&mut conn).is_err() {
+                return false;
+            }
+            drop(conn);
+            match self.read_tls() {
+                Ok(n) if n > 0 => (),
+                _ => return false,
+            }
+        }
+    }
+}
+
+impl Read for Tls {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.conn.lock().unwrap().reader().read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                r => return r,
+            }
+            if self.read_tls()? == 0 {
+                return Ok(0);
+            }
+        }
+    }
+}
+
+impl Write for Tls {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        let n = conn.writer().write(buf)?;
+        self.flush_tls(&mut conn)?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        self.flush_tls(&mut conn)
+    }
+}
+
+impl Transport for Tls {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Tls {
+            conn: self.conn.clone(),
+            sock: self.sock.try_clone()?,
+        })
+    }
+
+    fn close(&self) {
+        let mut conn = self.conn.lock().unwrap();
+        conn.send_close_notify();
+        let _ = self.flush_tls(&mut conn);
+        let _ = self.sock.shutdown(Shutdown::Both);
+    }
+
+    fn peer(&self) -> String {
+        Transport::peer(&self.sock)
+    }
+
+    /// Completes the handshake, the subject of the certificate
+    /// of the other end is tls:{subject}
+    fn identity(&mut self) -> String {
+        // a client that never finishes the handshake does not hold the thread
+        let _ = self.sock.set_read_timeout(Some(HANDSHAKE));
+        let done = self.handshake();
+        let _ = self.sock.set_read_timeout(None);
+        if !done {
+            let _ = self.sock.shutdown(Shutdown::Both);
+            return String::new();
+        }
+        let conn = self.conn.lock().unwrap();
+        let cert = match conn.peer_certificates().and_then(|c| c.first()) {
+            Some(cert) => cert,
+            None => return String::new(),
+        };
+        match x509_parser::parse_x509_certificate(cert) {
+            Ok((_, cert)) => format!("tls:{}", cert.subject()),
+            Err(_) => String::new(),
+        }
+    }
+}
@@ -0,0 +1,70 @@
+//! Streams and listeners the service and Connection run on
+
+use crate::{
+    fun::Fun,
+    link::{serve, Link},
+};
+use std::{
+    io::{self, Read, Write},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+/// A connected stream, implemented for TcpStream and UnixStream.
+/// Implement it to serve over streams of your own
+pub trait Transport: Read + Write + Send + Sized + 'static {
+    /// Another handle to the same stream,
+    /// one is read by a thread while the other is written
+    fn try_clone(&self) -> io::Result<Self>;
+
+    /// Shut the stream down, a thread blocked reading it returns
+    fn close(&self) {}
+
+    /// Who is on the other end, passed to the functions as Call::peer
+    fn peer(&self) -> String {
+        String::new()
+    }
+
+    /// Who the other end proved to be, checked by the rules of Fun::allow.
+    /// Called on the connection thread before it is served,
+    /// a handshake can be completed here
+    fn identity(&mut self) -> String {
+        String::new()
+    }
+}
+
+/// Accepts the connections of a service
+pub trait Listener {
+    type Stream: Transport;
+
+    /// Wait for the next connection, None ends the service
+    fn accept(&self) -> Option<Self::Stream>;
+}
+
+/// Serve every connection of the listener on its own thread,
+/// returns once it accepts no more
+pub fn service_on<L: Listener>(srv_fun: Fun, listener: L) {
+    let srv_fun = Arc::new(srv_fun);
+    while let Some(stream) = listener.accept() {
+        let srv_fun = srv_fun.clone();
+        thread::spawn(move || serve_stream(&srv_fun, stream));
+    }
+}
+
+/// Serve one connection on this thread until it ends
+pub(crate) fn serve_stream<T: Transport>(fun: &Fun, mut stream: T) {
+    let identity = stream.identity();
+    if let Ok((link, peer)) = link(stream) {
+        serve(fun, link, peer, identity);
+    }
+}
+
+pub(crate) fn link<T: Transport>(stream: T) -> io::Result<(Link, String)> {
+    let peer = stream.peer();
+    let r = stream.try_clone()?;
+    let c = Mutex::new(stream.try_clone()?);
+    Ok((
+        Link::new(r, stream, move || c.lock().unwrap().close()),
+        peer,
+    ))
+}
@@ -0,0 +1,108 @@
+//! Unix domain sockets, sharing the framing and dispatch of tcp
+
+use crate::{
+    fun::Fun,
+    transport::{service_on, Listener, Transport},
+};
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    net::Shutdown,
+    os::unix::{
+        fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Like service, listening on a unix domain socket at path.
+/// A socket file left behind by a server that is gone is removed,
+/// a socket that still accepts connections makes this panic.
+pub fn service_unix(srv_fun: Fun, path: &str) {
+    remove_stale(path);
+    service_on(srv_fun, UnixListener::bind(path).unwrap())
+}
+
+/// Like service_unix, the permission bits of the socket file
+/// are set to mode, e.g. 0o600 to only allow the owner.
+/// The socket is bound in a private directory and moved to path
+/// once its mode is set, so it is never reachable with other bits.
+pub fn service_unix_mode(srv_fun: Fun, path: &str, mode: u32) {
+    remove_stale(path);
+    let dir = format!("{}.{}", path, std::process::id());
+    fs::DirBuilder::new().mode(0o700).create(&dir).unwrap();
+    let tmp = Path::new(&dir).join("s");
+    let bound = UnixListener::bind(&tmp).and_then(|l| {
+        fs::set_permissions(&tmp, fs::Permissions::from_mode(mode))?;
+        fs::rename(&tmp, path)?;
+        Ok(l)
+    });
+    let _ = fs::remove_file(&tmp);
+    let _ = fs::remove_dir(&dir);
+    service_on(srv_fun, bound.unwrap())
+}
+
+impl Transport for UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+
+    fn close(&self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+
+    /// unix:{n}, the peers have no address, n numbers the connections
+    fn peer(&self) -> String {
+        static CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+        format!("unix:{}", CONNECTIONS.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+
+    /// The user id of the peer process, uid:{uid}
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn identity(&mut self) -> String {
+        use std::os::unix::io::AsRawFd;
+        let mut cred = libc::ucred {
+            pid: 0,
+            uid: 0,
+            gid: 0,
+        };
+        let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+        let r = unsafe {
+            libc::getsockopt(
+                self.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut cred as *mut libc::ucred as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        match r {
+            0 => format!("uid:{}", cred.uid),
+            _ => String::new(),
+        }
+    }
+}
+
+impl Listener for UnixListener {
+    type Stream = UnixStream;
+
+    fn accept(&self) -> Option<UnixStream> {
+        self.incoming().flatten().next()
+    }
+}
+
+fn remove_stale(path: &str) {
+    let meta = match fs::symlink_metadata(Path::new(path)) {
+        Ok(m) => m,
+        Err(_) => return,
+    };
+    if !meta.file_type().is_socket() {
+        panic!("{} exists and is not a socket", path);
+    }
+    match UnixStream::connect(path) {
+        Ok(_) => panic!("{} is in use by a running service", path),
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => fs::remove_file(path).unwrap(),
+        Err(e) => panic!("{}: {}", path, e),
+    }
+}
@@ -37,6 +37,13 @@ pub struct ByteQue {
     head: usize,
 }
 
+impl Default for ByteQue {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ByteQue {
     #[inline]
     pub fn new() -> Self {
@@ -70,7 +77,7 @@ impl ByteQue {
     }
     #[inline]
     pub fn pop(&mut self) -> u8 {
-        if self.len() == 0 {
+        if self.is_empty() {
             0
         } else {
             let x = self.head;
@@ -102,6 +109,15 @@ impl ByteQue {
     pub fn len(&self) -> usize {
         self.buf.len() - self.head
     }
+    /// The bytes that have not been popped yet
+    #[inline]
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buf[self.head..]
+    }
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 impl From<Vec<u8>> for ByteQue {
@@ -154,7 +170,7 @@ impl Store for usize {
         // 64-bit computers will overflow if this number is exceeded
         for i in 0..10 {
             let v = q.pop();
-            s |= (v as usize & 0x7f) << 7 * i;
+            s |= (v as usize & 0x7f) << (7 * i);
             if v <= 0x7f {
                 break;
             }
@@ -201,20 +217,13 @@ macro_rules! number_store {
         impl Store for $typ {
             #[inline]
             fn store(&self, q: &mut ByteQue) {
-                let mut s: [u8; $num] = unsafe { std::mem::transmute(*self) };
-                if cfg!(target_endian = "big") {
-                    s.reverse();
-                }
-                q.push_slice(&s);
+                q.push_slice(&self.to_le_bytes());
             }
             #[inline]
             fn restore(q: &mut ByteQue) -> Self {
                 let mut s = [0u8; $num];
                 s.copy_from_slice(q.pop_slice($num));
-                if cfg!(target_endian = "big") {
-                    s.reverse();
-                }
-                unsafe { std::mem::transmute(s) }
+                <$typ>::from_le_bytes(s)
             }
         }
     };
@@ -230,7 +239,18 @@ number_store!(i128, 16);
 number_store!(u128, 16);
 number_store!(f32, 4);
 number_store!(f64, 8);
-number_store!(char, 4);
+
+impl Store for char {
+    #[inline]
+    fn store(&self, q: &mut ByteQue) {
+        (*self as u32).store(q);
+    }
+    #[inline]
+    fn restore(q: &mut ByteQue) -> Self {
+        // an invalid value can only come from bad data
+        char::from_u32(u32::restore(q)).unwrap_or(char::REPLACEMENT_CHARACTER)
+    }
+}
 
 impl Store for String {
     #[inline]
@@ -257,9 +277,7 @@ impl Store for () {
     #[inline]
     fn store(&self, _: &mut ByteQue) {}
     #[inline]
-    fn restore(_: &mut ByteQue) -> Self {
-        ()
-    }
+    fn restore(_: &mut ByteQue) -> Self {}
 }
 
 impl<T> Store for Option<T>
@@ -0,0 +1,52 @@
+//! Connection::spawn of a child serving with serve_stdio,
+//! the child is this test run again with CHILD set
+
+use lrpc::*;
+use std::process::Command;
+
+const CHILD: &str = "LRPC_STDIO_CHILD";
+
+#[fmt_function]
+fn reverse(s: String) -> String {
+    s.chars().rev().collect()
+}
+
+#[fmt_function]
+fn pid() -> u32 {
+    std::process::id()
+}
+
+fn main() {
+    if std::env::var_os(CHILD).is_some() {
+        let mut srv_fun = Fun::new();
+        srv_fun.regist("reverse", reverse);
+        srv_fun.regist("pid", pid);
+        serve_stdio(srv_fun);
+        return;
+    }
+
+    let mut child = Command::new(std::env::current_exe().unwrap());
+    child.env(CHILD, "1");
+    let mut con = Connection::spawn(&mut child).unwrap();
+    let rst: Result<String> = con.invoke(fun!("reverse", "plugin".to_string()));
+    assert_eq!(rst, Ok("nigulp".to_string()));
+    let pid: Result<u32> = con.invoke(fun!("pid"));
+    assert!(matches!(pid, Ok(p) if p != std::process::id()));
+    drop(con);
+
+    assert!(Connection::spawn(&mut Command::new("lrpc-no-such-command")).is_err());
+
+    #[cfg(unix)]
+    {
+        let mut con = Connection::spawn(&mut Command::new("true")).unwrap();
+        let rst: Result<String> = con.invoke(fun!("reverse", "plugin".to_string()));
+        assert!(rst.is_err());
+
+        // ignores stdin closing, killed when dropped
+        let con = Connection::spawn(Command::new("sleep").arg("60")).unwrap();
+        let start = std::time::Instant::now();
+        drop(con);
+        assert!(start.elapsed() < std::time::Duration::from_secs(30));
+    }
+    println!("stdio ok");
+}